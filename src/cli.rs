@@ -0,0 +1,153 @@
+use clap::{Args, Parser, Subcommand};
+use std::time::Duration;
+use crate::{config::{Config, SerialOverrides}, schedule::{self, Window}};
+
+//Options shared by every subcommand. Anything given here overrides the config file.
+#[derive(Debug,Parser)]
+#[command(name = "seymour_poc_rust", version, about = "Life testing for Seymour units",
+    after_help = "Exit status: 0 finished, 1 error, 3 stopped by a signal with every unit at a safe point, 4 stopped before they all got there.")]
+pub struct Cli{
+    /// Config file (default: config.toml if present)
+    #[arg(long, global = true, value_name = "FILE")]
+    pub config: Option<String>,
+    /// Baud rate for every port
+    #[arg(long, global = true, value_name = "RATE")]
+    pub baud: Option<u32>,
+    /// Detect each port's baud rate
+    #[arg(long, global = true)]
+    pub auto_baud: bool,
+    /// Serial profile from the config file
+    #[arg(long, global = true, value_name = "NAME")]
+    pub serial_profile: Option<String>,
+    /// Network console, repeatable
+    #[arg(long, global = true, value_name = "HOST:PORT")]
+    pub network: Vec<String>,
+    /// Shell command that prints the unit's serial
+    #[arg(long, global = true, value_name = "COMMAND")]
+    pub serial_command: Option<String>,
+    /// Fixture file mapping slots to ports, relays and serials
+    #[arg(long, global = true, value_name = "FILE")]
+    pub fixture: Option<String>,
+    /// GPIO backend
+    #[arg(long, global = true, value_name = "rppal|cdev|mock")]
+    pub gpio_backend: Option<String>,
+    /// Without a subcommand the tool runs interactively, as `run` does
+    #[command(subcommand)]
+    pub command: Option<CliCommand>,
+}
+
+#[derive(Debug,Subcommand)]
+pub enum CliCommand{
+    /// List ports and fixture slots without sending anything to the units
+    Scan,
+    /// Identify units, assign relays and save the fixture file
+    Identify(EnrolArgs),
+    /// Identify units, then run the life test
+    Run(RunArgs),
+    /// Carry on the run that was interrupted, from its last checkpoint
+    Resume,
+    /// Print the totals recorded for every unit
+    Report,
+    /// Zero the totals recorded for one unit
+    Reset{
+        #[arg(long)]
+        serial: String,
+    },
+}
+
+#[derive(Clone,Debug,Default,Args)]
+pub struct EnrolArgs{
+    /// Identify every unit again instead of using the fixture file, then save the new map
+    #[arg(long)]
+    pub re_enrol: bool,
+    /// Exercise every relay before assigning them
+    #[arg(long)]
+    pub relay_self_test: bool,
+    /// Serial for the unit on a port or fixture slot, e.g. slot-3=ABC123
+    #[arg(long = "serial", value_name = "SLOT=SERIAL", value_parser = parse_assignment)]
+    pub serials: Vec<(String,String)>,
+    /// Fail instead of prompting for anything not given by flags or the fixture file
+    #[arg(long)]
+    pub no_prompt: bool,
+}
+
+#[derive(Clone,Debug,Default,Args)]
+pub struct RunArgs{
+    #[command(flatten)]
+    pub enrol: EnrolArgs,
+    /// Iterations to run; without it the run goes on until every unit meets its targets or the duration is up, or is prompted for when neither is set
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub iterations: Option<u64>,
+    /// Test plan file describing the steps of each cycle
+    #[arg(long, value_name = "FILE")]
+    pub plan: Option<String>,
+    /// Rhai script to run each cycle instead of a test plan
+    #[arg(long, value_name = "FILE")]
+    pub script: Option<String>,
+    /// Stop starting new cycles after this long, e.g. 72h
+    #[arg(long, value_name = "DURATION", value_parser = schedule::parse_duration)]
+    pub duration: Option<Duration>,
+    /// Only start cycles inside this daily window, e.g. 18:00-07:00; repeatable
+    #[arg(long = "window", value_name = "HH:MM-HH:MM", value_parser = Window::parse)]
+    pub windows: Vec<Window>,
+    /// Start afresh even if an interrupted session is waiting to be resumed
+    #[arg(long)]
+    pub discard_session: bool,
+}
+
+fn parse_assignment(input:&str) -> Result<(String,String),String>{
+    match input.split_once('='){
+        Some((slot, serial)) if !slot.is_empty() && !serial.is_empty() => return Ok((slot.to_string(), serial.to_string())),
+        _ => return Err(format!("Expected SLOT=SERIAL, got {}",input)),
+    }
+}
+
+impl Cli{
+    pub fn load_config(&self) -> Result<Config,String>{
+        let mut config = Config::load_or_default(self.config.as_deref())?;
+        config.serial.command_line = SerialOverrides {
+            profile: self.serial_profile.clone(),
+            baud_rate: self.baud,
+            auto_baud: self.auto_baud.then_some(true),
+            ..Default::default()
+        };
+        config.network.ports.extend(self.network.iter().cloned());
+        if let Some(ref fixture_path) = self.fixture{
+            config.fixture.path = fixture_path.clone();
+        }
+        if let Some(ref gpio_backend) = self.gpio_backend{
+            config.gpio.backend = gpio_backend.clone();
+        }
+        if self.serial_command.is_some(){
+            config.identification.command = self.serial_command.clone();
+        }
+        if let Some(CliCommand::Run(ref run)) = self.command{
+            if run.plan.is_some(){
+                config.plan.path = run.plan.clone();
+            }
+            if run.script.is_some(){
+                config.plan.script = run.script.clone();
+            }
+            if run.duration.is_some(){
+                config.schedule.duration = run.duration;
+            }
+            if !run.windows.is_empty(){
+                config.schedule.windows = run.windows.clone();
+            }
+        }
+        if let Some(enrol) = self.get_enrol_args(){
+            if enrol.relay_self_test{
+                config.relays.self_test = true;
+            }
+        }
+        return Ok(config);
+    }
+
+    pub fn get_enrol_args(&self) -> Option<&EnrolArgs>{
+        match self.command{
+            Some(CliCommand::Identify(ref enrol)) => return Some(enrol),
+            Some(CliCommand::Run(ref run)) => return Some(&run.enrol),
+            _ => return None,
+        }
+    }
+}
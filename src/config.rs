@@ -0,0 +1,688 @@
+use std::{collections::{HashMap, HashSet}, fs, path::Path, time::Duration};
+use serde::Deserialize;
+use serialport::{DataBits, Parity, StopBits, FlowControl};
+use crate::{tty::{SerialSettings, Command}, retry::RetryPolicy, lock, fixture, gpio_facade::{self, RelaySettings}, device::{PowerSettings, FailurePolicy}, pause::{self, PauseSettings}, status::{self, StatusSettings}, plan::TestPlan, script::TestScript, target::Targets, session, schedule::{self, Window}, shutdown, step};
+
+pub const DEFAULT_CONFIG_FILE: &str = "config.toml";
+
+#[derive(Clone,Debug,Default,Deserialize)]
+#[serde(default)]
+pub struct Config{
+    pub serial: SerialConfig,
+    pub network: NetworkConfig,
+    pub locking: LockConfig,
+    pub identification: IdentificationConfig,
+    pub fixture: FixtureConfig,
+    pub gpio: GpioConfig,
+    pub relays: RelayConfig,
+    pub power: PowerConfig,
+    pub pause: PauseConfig,
+    pub status: StatusConfig,
+    pub plan: PlanConfig,
+    pub targets: TargetConfig,
+    pub session: SessionConfig,
+    pub schedule: ScheduleConfig,
+    pub shutdown: ShutdownConfig,
+    pub quarantine: QuarantineConfig,
+    pub retry: RetryConfig,
+    pub limits: LimitConfig,
+}
+
+impl Config{
+    pub fn load(path:&str) -> Result<Self,String>{
+        let contents = fs::read_to_string(path).map_err(|error| format!("Unable to read config file {}: {}",path,error))?;
+        let config:Config = toml::from_str(&contents).map_err(|error| format!("Unable to parse config file {}: {}",path,error))?;
+        config.targets.validate().map_err(|error| format!("Invalid config file {}: {}",path,error))?;
+        config.retry.validate().map_err(|error| format!("Invalid config file {}: {}",path,error))?;
+        config.limits.validate().map_err(|error| format!("Invalid config file {}: {}",path,error))?;
+        return Ok(config);
+    }
+
+    //Loads the given file, or the default config file if it exists, or falls back to defaults.
+    pub fn load_or_default(path:Option<&str>) -> Result<Self,String>{
+        match path{
+            Some(path) => return Config::load(path),
+            None => {
+                if Path::new(DEFAULT_CONFIG_FILE).is_file(){
+                    return Config::load(DEFAULT_CONFIG_FILE);
+                }
+                return Ok(Config::default());
+            }
+        }
+    }
+}
+
+//Every field is optional so that the top-level defaults, named profiles, per-port
+//entries and command-line flags can be layered over each other.
+#[derive(Clone,Debug,Default,Deserialize)]
+#[serde(default)]
+pub struct SerialOverrides{
+    pub profile: Option<String>,
+    pub baud_rate: Option<u32>,
+    pub timeout_ms: Option<u64>,
+    pub data_bits: Option<u8>,
+    pub parity: Option<String>,
+    pub stop_bits: Option<u8>,
+    pub flow_control: Option<String>,
+    pub auto_baud: Option<bool>,
+    pub auto_baud_rates: Option<Vec<u32>>,
+}
+
+impl SerialOverrides{
+    fn apply(&self, settings:&mut SerialSettings) -> Result<(),String>{
+        if let Some(baud_rate) = self.baud_rate{
+            settings.baud_rate = baud_rate;
+        }
+        if let Some(timeout_ms) = self.timeout_ms{
+            settings.timeout = Duration::from_millis(timeout_ms);
+        }
+        if let Some(data_bits) = self.data_bits{
+            settings.data_bits = match data_bits{
+                5 => DataBits::Five,
+                6 => DataBits::Six,
+                7 => DataBits::Seven,
+                8 => DataBits::Eight,
+                _ => return Err(format!("Invalid data bits: {}",data_bits)),
+            };
+        }
+        if let Some(ref parity) = self.parity{
+            settings.parity = match parity.to_lowercase().as_str(){
+                "none" => Parity::None,
+                "odd" => Parity::Odd,
+                "even" => Parity::Even,
+                _ => return Err(format!("Invalid parity: {}",parity)),
+            };
+        }
+        if let Some(stop_bits) = self.stop_bits{
+            settings.stop_bits = match stop_bits{
+                1 => StopBits::One,
+                2 => StopBits::Two,
+                _ => return Err(format!("Invalid stop bits: {}",stop_bits)),
+            };
+        }
+        if let Some(ref flow_control) = self.flow_control{
+            settings.flow_control = match flow_control.to_lowercase().as_str(){
+                "none" => FlowControl::None,
+                "software" => FlowControl::Software,
+                "hardware" => FlowControl::Hardware,
+                _ => return Err(format!("Invalid flow control: {}",flow_control)),
+            };
+        }
+        if let Some(auto_baud) = self.auto_baud{
+            settings.auto_baud = auto_baud;
+        }
+        if let Some(ref auto_baud_rates) = self.auto_baud_rates{
+            settings.auto_baud_rates = auto_baud_rates.clone();
+        }
+        return Ok(());
+    }
+}
+
+#[derive(Clone,Debug,Default,Deserialize)]
+#[serde(default)]
+pub struct SerialConfig{
+    #[serde(flatten)]
+    pub defaults: SerialOverrides,
+    pub profiles: HashMap<String,SerialOverrides>,
+    //Keyed by either the full port path or its file name under /dev/serial/by-path.
+    pub ports: HashMap<String,SerialOverrides>,
+    //Set from the command line; wins over everything in the file.
+    #[serde(skip)]
+    pub command_line: SerialOverrides,
+}
+
+impl SerialConfig{
+    fn port_overrides(&self, port:&str) -> Option<&SerialOverrides>{
+        if let Some(overrides) = self.ports.get(port){
+            return Some(overrides);
+        }
+        let file_name = Path::new(port).file_name()?.to_string_lossy();
+        return self.ports.get(file_name.as_ref());
+    }
+
+    pub fn settings_for(&self, port:&str) -> Result<SerialSettings,String>{
+        let mut settings = SerialSettings::default();
+        let port_overrides = self.port_overrides(port);
+        let profile_name = self.command_line.profile.as_ref()
+            .or(port_overrides.and_then(|overrides| overrides.profile.as_ref()))
+            .or(self.defaults.profile.as_ref());
+
+        self.defaults.apply(&mut settings)?;
+        if let Some(profile_name) = profile_name{
+            match self.profiles.get(profile_name){
+                Some(profile) => profile.apply(&mut settings)?,
+                None => return Err(format!("Unknown serial profile: {}",profile_name)),
+            }
+        }
+        if let Some(overrides) = port_overrides{
+            overrides.apply(&mut settings)?;
+        }
+        self.command_line.apply(&mut settings)?;
+        return Ok(settings);
+    }
+}
+
+#[derive(Clone,Debug,Default,Deserialize)]
+#[serde(default)]
+pub struct NetworkConfig{
+    //Console server ports to probe alongside /dev/serial/by-path,
+    //as tcp://host:port, rfc2217://host:port or host:port.
+    pub ports: Vec<String>,
+}
+
+#[derive(Clone,Debug,Deserialize)]
+#[serde(default)]
+pub struct LockConfig{
+    pub enabled: bool,
+    pub directory: String,
+}
+
+impl Default for LockConfig{
+    fn default() -> Self {
+        LockConfig {
+            enabled: true,
+            directory: lock::DEFAULT_LOCK_DIRECTORY.to_string(),
+        }
+    }
+}
+
+impl LockConfig{
+    pub fn get_directory(&self) -> Option<String>{
+        if self.enabled{
+            return Some(self.directory.clone());
+        }
+        return None;
+    }
+}
+
+//How to read a unit's serial from its shell. Identification falls back to
+//asking the operator when neither is set, or when reading fails.
+#[derive(Clone,Debug,Deserialize)]
+#[serde(default)]
+pub struct IdentificationConfig{
+    pub command: Option<String>,
+    pub file: Option<String>,
+    //Regex that every serial, read or entered, must match in full.
+    pub serial_pattern: Option<String>,
+    //Extra pause between brightness toggles while waiting for the operator.
+    pub blink_interval_ms: u64,
+}
+
+impl Default for IdentificationConfig{
+    fn default() -> Self {
+        IdentificationConfig {
+            command: None,
+            file: None,
+            serial_pattern: None,
+            blink_interval_ms: 250,
+        }
+    }
+}
+
+impl IdentificationConfig{
+    pub fn get_command(&self) -> Option<String>{
+        if let Some(ref command) = self.command{
+            return Some(command.clone());
+        }
+        return self.file.as_ref().map(|file| format!("cat {}",file));
+    }
+}
+
+#[derive(Clone,Debug,Deserialize)]
+#[serde(default)]
+pub struct FixtureConfig{
+    pub path: String,
+    //When false the fixture file is ignored and every device is enrolled from scratch.
+    pub enabled: bool,
+}
+
+impl Default for FixtureConfig{
+    fn default() -> Self {
+        FixtureConfig {
+            path: fixture::DEFAULT_FIXTURE_FILE.to_string(),
+            enabled: true,
+        }
+    }
+}
+
+#[derive(Clone,Debug,Deserialize)]
+#[serde(default)]
+pub struct GpioConfig{
+    //"rppal", "cdev" or "mock"
+    pub backend: String,
+    //Character device used by the cdev backend.
+    pub chip: String,
+}
+
+impl Default for GpioConfig{
+    fn default() -> Self {
+        GpioConfig {
+            backend: gpio_facade::DEFAULT_GPIO_BACKEND.to_string(),
+            chip: gpio_facade::DEFAULT_GPIO_CHIP.to_string(),
+        }
+    }
+}
+
+#[derive(Clone,Debug,Deserialize)]
+pub struct RelayPin{
+    pub address: u8,
+    //Falls back to the relay board's active_low setting when not given.
+    pub active_low: Option<bool>,
+}
+
+#[derive(Clone,Debug,Deserialize)]
+#[serde(default)]
+pub struct RelayConfig{
+    pub pins: Vec<RelayPin>,
+    pub active_low: bool,
+    pub on_settle_ms: u64,
+    pub off_settle_ms: u64,
+    //Exercise every relay at startup and leave failing ones out of enrolment.
+    pub self_test: bool,
+}
+
+impl Default for RelayConfig{
+    fn default() -> Self {
+        RelayConfig {
+            pins: gpio_facade::RELAY_ADDRESSES.iter().map(|&address| RelayPin { address, active_low: None }).collect(),
+            active_low: false,
+            on_settle_ms: 0,
+            off_settle_ms: 0,
+            self_test: false,
+        }
+    }
+}
+
+impl RelayConfig{
+    pub fn get_settings(&self) -> RelaySettings{
+        return RelaySettings {
+            addresses: self.pins.iter().map(|pin| pin.address).collect(),
+            active_low: self.pins.iter()
+                .filter(|pin| pin.active_low.unwrap_or(self.active_low))
+                .map(|pin| pin.address)
+                .collect::<HashSet<u8>>(),
+            on_settle: Duration::from_millis(self.on_settle_ms),
+            off_settle: Duration::from_millis(self.off_settle_ms),
+        };
+    }
+}
+
+//Power relays are assigned per slot with power_relay in the fixture file.
+#[derive(Clone,Debug,Deserialize)]
+#[serde(default)]
+pub struct PowerConfig{
+    pub active_low: bool,
+    pub off_time_ms: u64,
+    pub hard_reboot_every: u64,
+    //0 disables automatic recovery.
+    pub unresponsive_timeout_s: u64,
+}
+
+impl Default for PowerConfig{
+    fn default() -> Self {
+        PowerConfig {
+            active_low: false,
+            off_time_ms: 5000,
+            hard_reboot_every: 0,
+            unresponsive_timeout_s: 0,
+        }
+    }
+}
+
+impl PowerConfig{
+    pub fn get_settings(&self) -> PowerSettings{
+        return PowerSettings {
+            off_time: Duration::from_millis(self.off_time_ms),
+            hard_reboot_every: self.hard_reboot_every,
+            unresponsive_timeout: match self.unresponsive_timeout_s{
+                0 => None,
+                seconds => Some(Duration::from_secs(seconds)),
+            },
+        };
+    }
+
+    pub fn get_relay_settings(&self, addresses:Vec<u8>) -> RelaySettings{
+        let active_low = match self.active_low{
+            true => addresses.iter().copied().collect(),
+            false => HashSet::new(),
+        };
+        return RelaySettings {
+            addresses,
+            active_low,
+            on_settle: Duration::ZERO,
+            off_settle: Duration::ZERO,
+        };
+    }
+}
+
+//Emergency-stop or pause switch. No pin means no pause input is watched.
+#[derive(Clone,Debug,Deserialize)]
+#[serde(default)]
+pub struct PauseConfig{
+    pub pin: Option<u8>,
+    pub active_low: bool,
+    pub pull_up: bool,
+    pub debounce_ms: u64,
+}
+
+impl Default for PauseConfig{
+    fn default() -> Self {
+        PauseConfig {
+            pin: None,
+            active_low: true,
+            pull_up: true,
+            debounce_ms: pause::DEFAULT_DEBOUNCE.as_millis() as u64,
+        }
+    }
+}
+
+impl PauseConfig{
+    pub fn get_settings(&self) -> Option<PauseSettings>{
+        return Some(PauseSettings {
+            address: self.pin?,
+            active_low: self.active_low,
+            pull_up: self.pull_up,
+            debounce: Duration::from_millis(self.debounce_ms),
+        });
+    }
+}
+
+//Stack light lamps. Per-slot LEDs are set with status_led in the fixture file.
+#[derive(Clone,Debug,Deserialize)]
+#[serde(default)]
+pub struct StatusConfig{
+    pub green: Option<u8>,
+    pub amber: Option<u8>,
+    pub red: Option<u8>,
+    pub active_low: bool,
+    pub blink_ms: u64,
+}
+
+impl Default for StatusConfig{
+    fn default() -> Self {
+        StatusConfig {
+            green: None,
+            amber: None,
+            red: None,
+            active_low: false,
+            blink_ms: status::DEFAULT_BLINK_INTERVAL.as_millis() as u64,
+        }
+    }
+}
+
+impl StatusConfig{
+    pub fn get_settings(&self) -> StatusSettings{
+        return StatusSettings {
+            green: self.green,
+            amber: self.amber,
+            red: self.red,
+            active_low: self.active_low,
+            blink_interval: Duration::from_millis(self.blink_ms),
+        };
+    }
+}
+
+#[derive(Clone,Debug,Default,Deserialize)]
+#[serde(default)]
+pub struct PlanConfig{
+    //Test plan file; without one every cycle is the standard boot, BP, temp and reboot.
+    pub path: Option<String>,
+    //Rhai script run in place of a plan.
+    pub script: Option<String>,
+}
+
+impl PlanConfig{
+    pub fn get_plan(&self) -> Result<TestPlan,String>{
+        match self.path{
+            Some(ref path) => return TestPlan::load(path),
+            None => return Ok(TestPlan::default()),
+        }
+    }
+
+    pub fn get_script(&self) -> Result<Option<TestScript>,String>{
+        match self.script{
+            Some(_) if self.path.is_some() => return Err("Set either a test plan or a script, not both".to_string()),
+            Some(ref path) => return Ok(Some(TestScript::load(path)?)),
+            None => return Ok(None),
+        }
+    }
+}
+
+//Lifetime targets, e.g. Reboots = 10000. Those at the top of [targets] apply to
+//every unit and replace the plan's; [targets.devices.<serial>] replaces them for one unit.
+#[derive(Clone,Debug,Default,Deserialize)]
+#[serde(default)]
+pub struct TargetConfig{
+    pub devices: HashMap<String,Targets>,
+    #[serde(flatten)]
+    pub program: Targets,
+}
+
+impl TargetConfig{
+    pub fn validate(&self) -> Result<(),String>{
+        self.program.validate().map_err(|error| format!("[targets]: {}",error))?;
+        for (serial, targets) in self.devices.iter(){
+            targets.validate().map_err(|error| format!("[targets.devices.{}]: {}",serial,error))?;
+        }
+        return Ok(());
+    }
+
+    pub fn get_targets(&self, plan:&TestPlan, serial:&str) -> Targets{
+        let mut targets = plan.targets.clone();
+        targets.merge(&self.program);
+        if let Some(device_targets) = self.devices.get(serial){
+            targets.merge(device_targets);
+        }
+        return targets;
+    }
+}
+
+//Checkpoint file kept while a run is in progress, for the resume command.
+#[derive(Clone,Debug,Deserialize)]
+#[serde(default)]
+pub struct SessionConfig{
+    pub path: String,
+}
+
+impl Default for SessionConfig{
+    fn default() -> Self {
+        SessionConfig {
+            path: session::DEFAULT_SESSION_FILE.to_string(),
+        }
+    }
+}
+
+//How long a run may go on, e.g. duration = "72h", and the daily windows it may
+//run in, e.g. windows = ["18:00-07:00"]. Without windows it may run at any time.
+#[derive(Clone,Debug,Default,Deserialize)]
+#[serde(default)]
+pub struct ScheduleConfig{
+    #[serde(deserialize_with = "schedule::deserialize_duration")]
+    pub duration: Option<Duration>,
+    pub windows: Vec<Window>,
+}
+
+//How long devices get to reach a safe point after SIGINT or SIGTERM.
+#[derive(Clone,Debug,Deserialize)]
+#[serde(default)]
+pub struct ShutdownConfig{
+    pub grace_s: u64,
+}
+
+impl Default for ShutdownConfig{
+    fn default() -> Self {
+        ShutdownConfig {
+            grace_s: shutdown::DEFAULT_GRACE.as_secs(),
+        }
+    }
+}
+
+#[derive(Clone,Copy,Debug,Default,Deserialize)]
+#[serde(default)]
+pub struct FailurePolicyOverrides{
+    pub consecutive_failures: Option<u64>,
+    pub failed_reboots: Option<u64>,
+}
+
+//When a failing unit is quarantined, e.g. consecutive_failures = 5. Those at
+//the top of [quarantine] apply to every unit; [quarantine.devices.<serial>]
+//replaces them for one unit. 0 turns a limit off.
+#[derive(Clone,Debug,Default,Deserialize)]
+#[serde(default)]
+pub struct QuarantineConfig{
+    pub devices: HashMap<String,FailurePolicyOverrides>,
+    #[serde(flatten)]
+    pub program: FailurePolicyOverrides,
+}
+
+impl QuarantineConfig{
+    pub fn get_policy(&self, serial:&str) -> FailurePolicy{
+        let mut policy = FailurePolicy::default();
+        let device = self.devices.get(serial).copied().unwrap_or_default();
+        for overrides in [self.program, device]{
+            if let Some(consecutive_failures) = overrides.consecutive_failures{
+                policy.consecutive_failures = consecutive_failures;
+            }
+            if let Some(failed_reboots) = overrides.failed_reboots{
+                policy.failed_reboots = failed_reboots;
+            }
+        }
+        return policy;
+    }
+}
+
+//Retry policies for individual device commands, keyed by command name, e.g.
+//[retry.commands.CheckBPState] attempts = 3. Only queries may be retried.
+//Plan steps take theirs from the plan file.
+#[derive(Clone,Debug,Default,Deserialize)]
+#[serde(default)]
+pub struct RetryConfig{
+    pub commands: HashMap<Command,RetryPolicy>,
+}
+
+impl RetryConfig{
+    pub fn validate(&self) -> Result<(),String>{
+        for (command, policy) in self.commands.iter(){
+            policy.validate().map_err(|error| format!("Retry policy for {:?}: {}",command,error))?;
+            //Sending StartBP again after a lost reply could start a second inflation.
+            if policy.attempts > 1 && !command.is_query(){
+                return Err(format!("{:?} cannot be retried; only queries (CheckBPState, ReadTemp) are safe to send twice",command));
+            }
+        }
+        return Ok(());
+    }
+}
+
+//How many devices may run each step kind at once, e.g. bp = 3 and reboot = 2
+//so the bench supply isn't browned out. Kinds not listed are unlimited.
+#[derive(Clone,Debug,Deserialize)]
+#[serde(default)]
+pub struct LimitConfig{
+    //Each device waits a random time up to this before its first cycle.
+    pub stagger_s: u64,
+    //A rebooting unit holds its place until its login prompt is back, or this long.
+    pub boot_budget_s: u64,
+    #[serde(flatten)]
+    pub operations: HashMap<String,usize>,
+}
+
+impl Default for LimitConfig{
+    fn default() -> Self {
+        LimitConfig {
+            stagger_s: 0,
+            boot_budget_s: step::DEFAULT_REBOOT_TIMEOUT.as_secs(),
+            operations: HashMap::new(),
+        }
+    }
+}
+
+impl LimitConfig{
+    pub fn get_stagger(&self) -> Duration{
+        return Duration::from_secs(self.stagger_s);
+    }
+
+    pub fn get_boot_budget(&self) -> Duration{
+        return Duration::from_secs(self.boot_budget_s);
+    }
+
+    //A misspelt kind would otherwise limit nothing without saying so.
+    pub fn validate(&self) -> Result<(),String>{
+        let kinds = step::StepRegistry::default().get_kinds();
+        let mut operations:Vec<&String> = self.operations.keys().collect();
+        operations.sort();
+        for operation in operations{
+            if !kinds.contains(operation){
+                return Err(format!("[limits] {} matches no step kind; expected one of {}",operation,kinds.join(", ")));
+            }
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn parse(text:&str) -> Config{
+        return toml::from_str(text).unwrap();
+    }
+
+    const LAYERED_SERIAL:&str = "[serial]
+baud_rate = 9600
+profile = \"slow\"
+[serial.profiles.slow]
+baud_rate = 19200
+timeout_ms = 2000
+[serial.profiles.fast]
+baud_rate = 115200
+[serial.ports.\"platform-usb-0:1.2\"]
+profile = \"fast\"
+parity = \"even\"
+[serial.ports.\"/dev/ttyACM0\"]
+baud_rate = 57600
+";
+
+    #[test]
+    fn serial_settings_layer_port_over_profile_over_defaults(){
+        let serial = parse(LAYERED_SERIAL).serial;
+        let unlisted = serial.settings_for("/dev/ttyUSB3").unwrap();
+        assert_eq!((unlisted.baud_rate, unlisted.timeout, unlisted.parity),(19200, Duration::from_millis(2000), Parity::None));
+        let by_path = serial.settings_for("/dev/serial/by-path/platform-usb-0:1.2").unwrap();
+        assert_eq!((by_path.baud_rate, by_path.timeout, by_path.parity),(115200, SerialSettings::default().timeout, Parity::Even));
+        let by_full_path = serial.settings_for("/dev/ttyACM0").unwrap();
+        assert_eq!((by_full_path.baud_rate, by_full_path.timeout),(57600, Duration::from_millis(2000)));
+    }
+
+    #[test]
+    fn the_command_line_wins_over_the_serial_config(){
+        let mut serial = parse(LAYERED_SERIAL).serial;
+        serial.command_line = SerialOverrides { profile: Some("fast".to_string()), auto_baud: Some(true), ..Default::default() };
+        let settings = serial.settings_for("/dev/ttyUSB3").unwrap();
+        assert_eq!((settings.baud_rate, settings.timeout, settings.auto_baud),(115200, SerialSettings::default().timeout, true));
+        serial.command_line.baud_rate = Some(230400);
+        assert_eq!(serial.settings_for("/dev/ttyACM0").unwrap().baud_rate,230400);
+    }
+
+    #[test]
+    fn unknown_profiles_and_bad_values_are_errors(){
+        let mut serial = parse(LAYERED_SERIAL).serial;
+        serial.command_line.profile = Some("turbo".to_string());
+        assert_eq!(serial.settings_for("/dev/ttyUSB3").unwrap_err(),"Unknown serial profile: turbo");
+        let serial = parse("[serial]\nparity = \"sideways\"\n").serial;
+        assert!(serial.settings_for("/dev/ttyUSB3").unwrap_err().starts_with("Invalid parity"));
+    }
+
+    #[test]
+    fn limits_on_step_kinds_are_accepted(){
+        let config = parse("[limits]\nstagger_s = 5\nbp = 3\nreboot = 2\n");
+        assert!(config.limits.validate().is_ok());
+        assert_eq!(config.limits.operations,HashMap::from([("bp".to_string(),3),("reboot".to_string(),2)]));
+        assert_eq!(config.limits.get_stagger(),Duration::from_secs(5));
+    }
+
+    #[test]
+    fn a_limit_that_matches_no_step_kind_is_rejected(){
+        let error = parse("[limits]\nbp = 3\nbloodpressure = 1\n").limits.validate().unwrap_err();
+        assert!(error.starts_with("[limits] bloodpressure matches no step kind"),"{}",error);
+    }
+}
@@ -0,0 +1,132 @@
+use std::{collections::{hash_map::RandomState, HashMap}, hash::BuildHasher, sync::{Arc, Condvar, Mutex}, time::Duration};
+
+//A fixed number of places for one kind of operation.
+#[derive(Debug)]
+struct Slots{
+    limit: usize,
+    in_use: Mutex<usize>,
+    freed: Condvar,
+}
+
+//Shared by every device thread so that no more than the configured number of
+//devices run the same kind of operation at once, e.g. at most 3 BP inflations
+//drawing on the bench supply together. Operations are named by step kind;
+//devices take reboot and bp places themselves for every reboot, power cycle
+//and BP start, whether from a plan, a script or recovery.
+#[derive(Debug,Default)]
+pub struct Coordinator{
+    slots: HashMap<String,Arc<Slots>>,
+    stagger: Duration,
+    boot_budget: Duration,
+}
+
+//Holds a place for an operation; the place is given back when this is dropped.
+#[derive(Debug)]
+pub struct OperationPermit{
+    slots: Arc<Slots>,
+}
+
+impl Coordinator{
+    //A limit of 0 leaves the operation unlimited.
+    pub fn new(limits:&HashMap<String,usize>, stagger:Duration, boot_budget:Duration) -> Self{
+        let slots = limits.iter()
+            .filter(|(_, limit)| **limit > 0)
+            .map(|(operation, limit)| (operation.clone(), Arc::new(Slots { limit: *limit, in_use: Mutex::new(0), freed: Condvar::new() })))
+            .collect();
+        return Coordinator { slots, stagger, boot_budget };
+    }
+
+    pub fn get_limit(&self, operation:&str) -> Option<usize>{
+        return self.slots.get(operation).map(|slots| slots.limit);
+    }
+
+    //How long a unit keeps its reboot place while waiting to come back up.
+    pub fn get_boot_budget(&self) -> Duration{
+        return self.boot_budget;
+    }
+
+    //Blocks until a place is free. None for operations without a limit.
+    pub fn acquire(&self, operation:&str, serial:&str) -> Option<OperationPermit>{
+        let slots = self.slots.get(operation)?;
+        let mut in_use = slots.in_use.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if *in_use >= slots.limit{
+            log::debug!("Device {} waiting for one of {} {} places",serial,slots.limit,operation);
+        }
+        while *in_use >= slots.limit{
+            in_use = slots.freed.wait(in_use).unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+        *in_use += 1;
+        return Some(OperationPermit { slots: slots.clone() });
+    }
+
+    //A random delay of up to the stagger, so devices don't all start their
+    //first cycle together.
+    pub fn get_stagger_delay(&self) -> Duration{
+        if self.stagger.is_zero(){
+            return Duration::ZERO;
+        }
+        let random = RandomState::new().hash_one(std::thread::current().id());
+        let millis = random % (self.stagger.as_millis() as u64 + 1);
+        return Duration::from_millis(millis);
+    }
+}
+
+impl Drop for OperationPermit{
+    fn drop(&mut self){
+        let mut in_use = self.slots.in_use.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        *in_use = in_use.saturating_sub(1);
+        self.slots.freed.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use std::{sync::atomic::{AtomicUsize, Ordering}, thread};
+
+    #[test]
+    fn unlimited_operations_need_no_permit(){
+        let coordinator = Coordinator::new(&HashMap::from([("bp".to_string(),0)]),Duration::ZERO,Duration::ZERO);
+        assert_eq!(coordinator.get_limit("bp"),None);
+        assert!(coordinator.acquire("bp","RES1").is_none());
+        assert!(coordinator.acquire("reboot","RES1").is_none());
+    }
+
+    #[test]
+    fn permits_are_given_back_when_dropped(){
+        let coordinator = Coordinator::new(&HashMap::from([("bp".to_string(),1)]),Duration::ZERO,Duration::ZERO);
+        assert_eq!(coordinator.get_limit("bp"),Some(1));
+        let permit = coordinator.acquire("bp","RES1");
+        assert!(permit.is_some());
+        drop(permit);
+        assert!(coordinator.acquire("bp","RES2").is_some());
+    }
+
+    #[test]
+    fn no_more_than_the_limit_run_at_once(){
+        let coordinator = Arc::new(Coordinator::new(&HashMap::from([("bp".to_string(),2)]),Duration::ZERO,Duration::ZERO));
+        let running = Arc::new(AtomicUsize::new(0));
+        let most = Arc::new(AtomicUsize::new(0));
+        let threads:Vec<_> = (0..6).map(|index| {
+            let (coordinator, running, most) = (coordinator.clone(), running.clone(), most.clone());
+            thread::spawn(move || {
+                let _permit = coordinator.acquire("bp",&format!("RES{}",index));
+                let now = running.fetch_add(1,Ordering::SeqCst) + 1;
+                most.fetch_max(now,Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(20));
+                running.fetch_sub(1,Ordering::SeqCst);
+            })
+        }).collect();
+        for thread in threads{
+            thread.join().unwrap();
+        }
+        assert!(most.load(Ordering::SeqCst) <= 2);
+    }
+
+    #[test]
+    fn stagger_delay_stays_within_the_stagger(){
+        let coordinator = Coordinator::new(&HashMap::new(),Duration::from_millis(500),Duration::ZERO);
+        assert!(coordinator.get_stagger_delay() <= Duration::from_millis(500));
+        assert_eq!(Coordinator::default().get_stagger_delay(),Duration::ZERO);
+    }
+}
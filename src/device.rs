@@ -1,34 +1,236 @@
-use std::{fs::{self, File}, path::Path, io::Write, thread, time::Duration};
+use std::{fs::{self, File}, path::Path, io::Write, thread, time::{Duration, Instant}};
+use std::collections::HashMap;
+use chrono::Local;
 use crate::tty::{TTY, Response,Command};
-use rppal::gpio::{Gpio,OutputPin};
-
-const BOOT_TIME:Duration = Duration::new(60, 0);
-const BP_RUN:Duration = Duration::new(75, 0);
-const REBOOTS_SECTION: &str = "Reboots: ";
-const BP_SECTION: &str = "Successful BP tests: ";
-const TEMP_SECTION: &str = "Successful temp tests: ";
-const OUTPUT_FOLDER: &str = "output/";
+use std::sync::Arc;
+use crate::gpio_facade::{RelayAllocator,RelayLease,RelaySettings};
+use crate::pause::PauseSignal;
+use crate::shutdown::ShutdownSignal;
+use crate::status::StatusBoard;
+use crate::plan::{TestPlan, PlannedStep};
+use crate::step::{self, TestStep, DEFAULT_COMMAND_TIMEOUT};
+use crate::target::{Targets, TARGETS_MET};
+use crate::session::{Session, DeviceProgress, CyclePosition};
+use crate::retry::RetryPolicy;
+use crate::coordinator::{Coordinator, OperationPermit};
+use serde::{Deserialize, Serialize};
+
+pub const REBOOTS: &str = "Reboots";
+pub const BP_TESTS: &str = "Successful BP tests";
+pub const TEMP_TESTS: &str = "Successful temp tests";
+pub const HARD_REBOOTS: &str = "Hard power cycles";
+//The counts every unit keeps, and the only ones targets may name.
+pub const COUNT_LABELS:[&str;4] = [REBOOTS, BP_TESTS, TEMP_TESTS, HARD_REBOOTS];
+//Kept apart from the failures above: a retry that then succeeds points at the link, not the unit.
+pub const COMMAND_RETRIES: &str = "Command retries";
+pub const STEP_RETRIES: &str = "Step retries";
+//Tests keep their units' files out of the real output folder.
+const OUTPUT_FOLDER: &str = if cfg!(test) { "target/test-output/" } else { "output/" };
 const UNINITIALISED_SERIAL: &str = "uninitialised";
+const LOGIN_PROMPT_TIMEOUT:Duration = Duration::new(120, 0);
+//The end of a login prompt wait in which a booting unit is nudged again, in
+//case its prompt went by unread.
+const LOGIN_NUDGE_WINDOW:Duration = Duration::new(10, 0);
+const POWER_OFF_TIME:Duration = Duration::new(5, 0);
+const OUTPUT_START_MARKER: &str = "OUTPUT_START";
+const OUTPUT_END_MARKER: &str = "OUTPUT_END";
+const QUARANTINE_SUFFIX: &str = "-quarantine.log";
 #[derive(PartialEq,Debug)]
 pub enum State{
     LoginPrompt,
+    Shell,
     DebugMenu,
     LifecycleMenu,
     BrightnessMenu
 }
 
+//How a device's power relay is used. Energising the relay cuts power, so
+//a released (or unpowered) relay leaves the unit running.
+#[derive(Clone,Debug)]
+pub struct PowerSettings{
+    pub off_time: Duration,
+    //Replace every Nth soft reboot with a power cycle; 0 never does.
+    pub hard_reboot_every: u64,
+    //Power cycle a unit that has sent nothing for this long.
+    pub unresponsive_timeout: Option<Duration>,
+}
+
+impl Default for PowerSettings{
+    fn default() -> Self {
+        PowerSettings {
+            off_time: POWER_OFF_TIME,
+            hard_reboot_every: 0,
+            unresponsive_timeout: None,
+        }
+    }
+}
+
+pub const DEFAULT_FAILURE_LIMIT: u64 = 10;
+pub const DEFAULT_FAILED_REBOOT_LIMIT: u64 = 3;
+
+//When a unit is given up on and taken out of the run. 0 never does.
+#[derive(Clone,Debug,PartialEq)]
+pub struct FailurePolicy{
+    //Failed steps in a row, of any kind.
+    pub consecutive_failures: u64,
+    //Failed reboots over the whole run.
+    pub failed_reboots: u64,
+}
+
+impl Default for FailurePolicy{
+    fn default() -> Self {
+        FailurePolicy {
+            consecutive_failures: DEFAULT_FAILURE_LIMIT,
+            failed_reboots: DEFAULT_FAILED_REBOOT_LIMIT,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Device{
     usb_tty:TTY,
     output_file: Option<File>,
-    gpio: rppal::gpio::Gpio,
-    address: Option<u8>,
-    pin: Option<OutputPin>,
+    relays: Arc<RelayAllocator>,
+    pin: Option<RelayLease>,
+    power: Option<RelayLease>,
+    power_settings: PowerSettings,
+    pause: Option<PauseSignal>,
+    shutdown: Option<ShutdownSignal>,
+    status: Option<Arc<StatusBoard>>,
+    targets: Targets,
+    session: Option<Arc<Session>>,
+    resume_at: Option<CyclePosition>,
+    failure_policy: FailurePolicy,
+    command_retries: HashMap<Command,RetryPolicy>,
+    coordinator: Option<Arc<Coordinator>>,
+    //Places this unit holds with the coordinator, by operation.
+    held: HashMap<String,OperationPermit>,
+    consecutive_failures: u64,
+    failed_reboots: u64,
+    recovery_attempted: bool,
+    quarantined: bool,
+    cycles: u64,
     serial: String,
     current_state: State,
-    reboots: u64,
-    temps: u64,
-    bps: u64
+    counts: Counts,
+}
+
+fn extract_output(output:&str) -> Option<String>{
+    let (_, after_start) = output.split_once(OUTPUT_START_MARKER)?;
+    let (command_output, _) = after_start.split_once(OUTPUT_END_MARKER)?;
+    return Some(command_output.trim().to_string());
+}
+
+fn valid_serial(serial:&str) -> Option<String>{
+    if serial.is_empty() || serial.contains(char::is_whitespace){
+        return None;
+    }
+    return Some(serial.to_string());
+}
+
+//Totals recorded in a unit's output file, one "label: value" line each. The
+//built-in labels are always present; test steps can add their own.
+#[derive(Clone,Debug,Deserialize,Serialize,PartialEq)]
+#[serde(transparent)]
+pub struct Counts{
+    values: Vec<(String,u64)>,
+}
+
+impl Default for Counts{
+    fn default() -> Self {
+        return Counts { values: COUNT_LABELS.iter().map(|label| (label.to_string(), 0)).collect() };
+    }
+}
+
+impl Counts{
+    pub fn get(&self, label:&str) -> u64{
+        return self.values.iter().find(|(name, _)| name == label).map(|(_, value)| *value).unwrap_or(0);
+    }
+
+    pub fn set(&mut self, label:&str, value:u64){
+        match self.values.iter_mut().find(|(name, _)| name == label){
+            Some(entry) => entry.1 = value,
+            None => self.values.push((label.to_string(), value)),
+        }
+    }
+
+    pub fn increment(&mut self, label:&str) -> u64{
+        let value = self.get(label) + 1;
+        self.set(label,value);
+        return value;
+    }
+
+    pub fn remove(&mut self, label:&str){
+        self.values.retain(|(name, _)| name != label);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, u64)>{
+        return self.values.iter().map(|(name, value)| (name.as_str(), *value));
+    }
+
+    fn parse(contents:&str) -> Self{
+        let mut counts = Counts::default();
+        for line in contents.lines().filter(|line| !line.is_empty()){
+            log::trace!("{:?}",line);
+            let (label, value) = match line.split_once(": "){
+                Some(label_and_value) => label_and_value,
+                None => {
+                    log::warn!("Unable to parse line [{}] from output file",line);
+                    continue;
+                }
+            };
+            match value.trim().parse::<u64>(){
+                Ok(value) => counts.set(label,value),
+                Err(_) => log::warn!("Unable to parse value [{}] into integer",value),
+            }
+        }
+        return counts;
+    }
+
+    fn render(&self) -> String{
+        return self.values.iter().map(|(label, value)| format!("{}: {}\n",label,value)).collect();
+    }
+}
+
+pub(crate) fn output_path(serial:&str) -> String{
+    return OUTPUT_FOLDER.to_owned() + serial + ".txt";
+}
+
+pub(crate) fn quarantine_path(serial:&str) -> String{
+    return OUTPUT_FOLDER.to_owned() + serial + QUARANTINE_SUFFIX;
+}
+
+pub fn read_counts(serial:&str) -> Result<Counts,String>{
+    let path = output_path(serial);
+    let contents = fs::read_to_string(&path).map_err(|error| format!("Unable to read {}: {}",path,error))?;
+    return Ok(Counts::parse(&contents));
+}
+
+//Serials with an output file, sorted. Units that were never identified are left out.
+pub fn recorded_serials() -> Vec<String>{
+    let mut serials:Vec<String> = match fs::read_dir(OUTPUT_FOLDER){
+        Ok(entries) => entries.filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|extension| extension == "txt"))
+            .filter_map(|path| Some(path.file_stem()?.to_string_lossy().to_string()))
+            .filter(|serial| serial != UNINITIALISED_SERIAL)
+            .collect(),
+        Err(error) => {
+            log::debug!("{}",error);
+            Vec::new()
+        }
+    };
+    serials.sort();
+    return serials;
+}
+
+//Zeroes a unit's totals, e.g. after it has been repaired. Errors if it has no output file.
+pub fn reset_counts(serial:&str) -> Result<(),String>{
+    let path = output_path(serial);
+    if !Path::new(&path).is_file(){
+        return Err(format!("No results recorded for {}",serial));
+    }
+    return fs::write(&path, Counts::default().render()).map_err(|error| format!("Unable to write {}: {}",path,error));
 }
 
 impl Device{
@@ -37,7 +239,9 @@ impl Device{
             _ = fs::create_dir(&OUTPUT_FOLDER);
         };
         log::debug!("{:?}",&self.serial);
-        let output_path = OUTPUT_FOLDER.to_owned() + &self.serial + ".txt";
+        let output_path = output_path(&self.serial);
+        //Counts belong to the serial: a unit identified late starts from its own file.
+        self.counts = Counts::default();
         if ! Path::new(&output_path).exists(){
             log::debug!("Creating file {}",output_path);
             let temp = fs::File::create(&output_path);
@@ -55,36 +259,8 @@ impl Device{
             let temp = std::fs::read_to_string(output_path);
             match temp{
                 Ok(file_contents) =>{
-                    let file_lines = file_contents.split("\n");
                     log::trace!("{:?}",file_contents);
-                    for line in file_lines {
-                        if line.len() > 0{
-                            log::trace!("{:?}",line);
-                            let section_and_data:Vec<&str> = line.split(": ").collect();
-                            let section = section_and_data[0];
-                            let possible_value = section_and_data[1].trim().parse::<u64>();
-                            match possible_value{
-                                Ok(value) => {
-                                    log::trace!("{:?} value: [{:?}]",section,value);
-                                    match section {
-                                        REBOOTS_SECTION => {
-                                            self.reboots = value;
-                                        },
-                                        BP_SECTION => {
-                                            self.bps = value;
-                                        },
-                                        TEMP_SECTION => {
-                                            self.temps = value;
-                                        },
-                                        _ => ()
-                                    };
-                                }
-                                Err(_) => {
-                                    log::warn!("Unable to parse value [{}] into integer",section_and_data[1]);
-                                }
-                            }
-                        };
-                    };
+                    self.counts = Counts::parse(&file_contents);
                 },
                 Err(error) => {
                     log::warn!("Could not load from file!");
@@ -94,7 +270,20 @@ impl Device{
         };
         return true
     }
-    pub fn new(mut usb_port:TTY,response:Option<Response>) -> Result<Self,String>{
+    #[cfg(feature = "hardware-gpio")]
+    pub fn new(usb_port:TTY,response:Option<Response>) -> Result<Self,String>{
+        let gpio = crate::gpio_facade::RppalBackend::new()?;
+        return Device::new_with_relays(usb_port,response,RelayAllocator::new(Arc::new(gpio),RelaySettings::default()));
+    }
+    //Without Pi GPIO there are no relays to drive, so temp tests are skipped.
+    #[cfg(not(feature = "hardware-gpio"))]
+    pub fn new(usb_port:TTY,response:Option<Response>) -> Result<Self,String>{
+        log::warn!("Built without hardware GPIO; temp tests are disabled.");
+        let gpio = crate::gpio_facade::MockBackend::new();
+        let no_relays = RelaySettings { addresses: Vec::new(), ..Default::default() };
+        return Device::new_with_relays(usb_port,response,RelayAllocator::new(Arc::new(gpio),no_relays));
+    }
+    pub fn new_with_relays(mut usb_port:TTY,response:Option<Response>,relays:Arc<RelayAllocator>) -> Result<Self,String>{
         let initial_state:State;
         match response{
             Some(response_value)=> {
@@ -104,9 +293,11 @@ impl Device{
                         _ = usb_port.read_from_device(None);
                         initial_state = State::LoginPrompt;
                     },
-                    Response::Other | Response::Empty | Response::ShellPrompt 
+                    Response::Other | Response::Empty
                         | Response::LoginPrompt | Response::Rebooting => 
                             initial_state = State::LoginPrompt,
+                    Response::ShellPrompt =>
+                            initial_state = State::Shell,
                     Response::BPOn | Response::BPOff | Response::TempFailed 
                         | Response::TempSuccess =>
                             initial_state = State::LifecycleMenu,
@@ -116,43 +307,56 @@ impl Device{
             },
             None => initial_state = State::LoginPrompt
         };
-        let temp = Gpio::new();
-        match temp{
-            Ok(gpio) =>{
-                let mut output = Self{
-                    usb_tty: usb_port,
-                    gpio,
-                    address: None,
-                    pin: None,
-                    output_file: None,
-                    serial: UNINITIALISED_SERIAL.to_string(),
-                    current_state: initial_state,
-                    reboots: 0,
-                    temps: 0,
-                    bps: 0
-                };
-                if !output.load_values(){
-                    log::warn!("Could not load values from file! File may be overwritten.");
-                }
-                return Ok(output);
-            }
-            Err(error) => {
-                log::warn!("Failed to init GPIO!");
-                log::debug!("{}",error);
-                return Err("Failed GPIO init".to_string());
-            }
+        let mut output = Self{
+            usb_tty: usb_port,
+            relays,
+            pin: None,
+            power: None,
+            power_settings: PowerSettings::default(),
+            pause: None,
+            shutdown: None,
+            status: None,
+            targets: Targets::default(),
+            session: None,
+            resume_at: None,
+            failure_policy: FailurePolicy::default(),
+            command_retries: HashMap::new(),
+            coordinator: None,
+            held: HashMap::new(),
+            consecutive_failures: 0,
+            failed_reboots: 0,
+            recovery_attempted: false,
+            quarantined: false,
+            cycles: 0,
+            output_file: None,
+            serial: UNINITIALISED_SERIAL.to_string(),
+            current_state: initial_state,
+            counts: Counts::default(),
+        };
+        if !output.load_values(){
+            log::warn!("Could not load values from file! File may be overwritten.");
         }
+        return Ok(output);
     }
 
     fn go_to_login_prompt(&mut self) -> &mut Self{
         while !(self.current_state == State::LoginPrompt){
             match self.current_state {
                 State::LoginPrompt => return self,
+                State::Shell => {
+                    self.usb_tty.write_to_device(Command::Logout);
+                    _ = self.usb_tty.read_from_device(None);
+                    self.current_state = State::LoginPrompt;
+                    return self;
+                },
                 State::DebugMenu | State::LifecycleMenu | State::BrightnessMenu => {
+                    self.hold(step::REBOOT);
                     self.usb_tty.write_to_device(Command::Quit);
                     _ = self.usb_tty.read_from_device(None);
                     self.current_state = State::LoginPrompt;
-                    self.reboots+=1;
+                    //Not counted here: only reboots made as a test are, by the
+                    //step or script that asked for them.
+                    self.finish_boot();
                     return self;
                 },
             };
@@ -175,6 +379,11 @@ impl Device{
                     self.current_state = State::BrightnessMenu;
                     return self;
                 },
+                State::Shell => {
+                    self.usb_tty.write_to_device(Command::DebugMenu);
+                    _ = self.usb_tty.read_from_device(None);
+                    self.current_state = State::DebugMenu;
+                },
                 State::LoginPrompt => {
                     self.usb_tty.write_to_device(Command::Login);
                     _ = self.usb_tty.read_from_device(None);
@@ -186,7 +395,6 @@ impl Device{
         };
         return self;
     }
-    #[allow(dead_code)]
     fn go_to_debug_menu(&mut self) -> &mut Self{
         while !(self.current_state == State::DebugMenu){
             match self.current_state {
@@ -201,6 +409,11 @@ impl Device{
                     _ = self.usb_tty.read_from_device(None);
                     self.current_state = State::BrightnessMenu;
                 },
+                State::Shell => {
+                    self.usb_tty.write_to_device(Command::DebugMenu);
+                    _ = self.usb_tty.read_from_device(None);
+                    self.current_state = State::DebugMenu;
+                },
                 State::LoginPrompt => {
                     self.usb_tty.write_to_device(Command::Login);
                     _ = self.usb_tty.read_from_device(None);
@@ -229,6 +442,11 @@ impl Device{
                     self.current_state = State::LifecycleMenu;
                     return self;
                 },
+                State::Shell => {
+                    self.usb_tty.write_to_device(Command::DebugMenu);
+                    _ = self.usb_tty.read_from_device(None);
+                    self.current_state = State::DebugMenu;
+                },
                 State::LoginPrompt => {
                     self.usb_tty.write_to_device(Command::Login);
                     _ = self.usb_tty.read_from_device(None);
@@ -241,7 +459,7 @@ impl Device{
         return self;
     }
     fn save_values(&mut self) -> bool{
-        let output_path = OUTPUT_FOLDER.to_owned() + &self.serial + ".txt";
+        let output_path = output_path(&self.serial);
         let temp = fs::OpenOptions::new().write(true).truncate(true).open(output_path);
         match temp{
             Ok(opened_file) => self.output_file = Some(opened_file),
@@ -251,17 +469,9 @@ impl Device{
             }
         }
         log::trace!("{:?}",self.output_file);
+        let output_data = self.get_counts().render();
         if let Some(ref mut file_name) = self.output_file{
             log::debug!("Writing to file!");
-            let mut output_data = REBOOTS_SECTION.to_string();
-            output_data.push_str(&self.reboots.to_string());
-            output_data.push_str("\n");
-            output_data.push_str(BP_SECTION);
-            output_data.push_str(&self.bps.to_string());
-            output_data.push_str("\n");
-            output_data.push_str(TEMP_SECTION);
-            output_data.push_str(&self.temps.to_string());
-            output_data.push_str("\n");
             let temp = file_name.write_all(output_data.as_bytes());
             match temp{
                 Err(error) => {
@@ -281,14 +491,100 @@ impl Device{
         self.save_values();
         return self;
     }
+    //Back to unidentified. Nothing is written until a serial is set again.
+    pub fn clear_serial(&mut self) -> &mut Self{
+        self.serial = UNINITIALISED_SERIAL.to_string();
+        self.counts = Counts::default();
+        return self;
+    }
+    pub fn get_counts(&self) -> Counts{
+        return self.counts.clone();
+    }
     pub fn get_serial(&mut self) -> &str{
         &self.serial
     }
+    pub fn get_port_name(&self) -> String{
+        return self.usb_tty.get_name();
+    }
+    pub fn get_pin_address(&self) -> Option<u8>{
+        return self.pin.as_ref().map(|pin| pin.get_address());
+    }
+    pub fn has_serial(&self) -> bool{
+        return self.serial != UNINITIALISED_SERIAL;
+    }
+    //A silent unit is nudged with newlines for its prompt. Once it prints
+    //anything it is only listened to, as a keypress during boot can stop it in
+    //its bootloader, until the last few seconds of the wait.
+    pub fn wait_for_login_prompt(&mut self, timeout:Duration) -> bool{
+        let start = std::time::Instant::now();
+        let mut booting = false;
+        while start.elapsed() < timeout{
+            match self.usb_tty.read_from_device(None){
+                Response::LoginPrompt => return true,
+                Response::Empty => {
+                    if !booting || timeout.saturating_sub(start.elapsed()) <= LOGIN_NUDGE_WINDOW{
+                        self.usb_tty.write_to_device(Command::Newline);
+                    }
+                },
+                _ => booting = true,
+            }
+        }
+        return false;
+    }
+    //Logs in to the shell and runs the given command, returning whatever it printed.
+    //The markers are split with empty quotes so the echoed command line never matches.
+    pub fn run_shell_command(&mut self, command:&str, timeout:Duration) -> Option<String>{
+        if self.current_state != State::Shell{
+            if self.current_state != State::LoginPrompt{
+                log::info!("Rebooting device on {:?} to reach its shell...",self.usb_tty);
+                self.go_to_login_prompt();
+                if !self.wait_for_login_prompt(LOGIN_PROMPT_TIMEOUT){
+                    log::warn!("Device on {:?} did not come back to a login prompt",self.usb_tty);
+                    return None;
+                }
+            }
+            self.usb_tty.write_to_device(Command::Login);
+            if self.usb_tty.read_from_device(None) != Response::ShellPrompt{
+                log::warn!("Could not log in to device on {:?}",self.usb_tty);
+                return None;
+            }
+            self.current_state = State::Shell;
+        }
+        let start_marker = OUTPUT_START_MARKER.replacen('_',"\"\"_",1);
+        let end_marker = OUTPUT_END_MARKER.replacen('_',"\"\"_",1);
+        self.usb_tty.write_raw(&format!("echo {}; {}; echo {}\n",start_marker,command,end_marker));
+        let start = Instant::now();
+        let mut output = String::new();
+        loop{
+            output.push_str(&self.usb_tty.read_raw());
+            if let Some(command_output) = extract_output(&output){
+                return Some(command_output);
+            }
+            if start.elapsed() >= timeout{
+                log::warn!("Command {:?} did not finish on device on {:?}",command,self.usb_tty);
+                log::debug!("{:?}",output);
+                return None;
+            }
+        }
+    }
+    pub fn read_serial(&mut self, command:&str) -> Option<String>{
+        let output = self.run_shell_command(command,DEFAULT_COMMAND_TIMEOUT)?;
+        let serial = valid_serial(&output);
+        match serial{
+            Some(ref serial) => log::info!("Read serial {} from device on {:?}",serial,self.usb_tty),
+            None => {
+                log::warn!("Could not read a serial from device on {:?}",self.usb_tty);
+                log::debug!("{:?}",output);
+            }
+        }
+        return serial;
+    }
     pub fn set_pin_address(&mut self, address:u8) -> &mut Self{
-        self.address = Some(address.clone());
-        let temp = self.gpio.get(address);
+        //Hand back the previous relay before leasing a new one.
+        self.pin = None;
+        let temp = self.relays.lease(address);
         match temp{
-            Ok(pin) => self.pin = Some(pin.into_output()),
+            Ok(pin) => self.pin = Some(pin),
             Err(error) => {
                 log::warn!("Could not set pin to this address {}; already assigned?",address);
                 log::debug!("{}",error);
@@ -296,60 +592,259 @@ impl Device{
         }
         return self;
     }
+    pub fn clear_pin_address(&mut self) -> &mut Self{
+        self.pin = None;
+        return self;
+    }
+    pub fn set_power_relay(&mut self, power:RelayLease, settings:PowerSettings) -> &mut Self{
+        self.power = Some(power);
+        self.power_settings = settings;
+        return self;
+    }
+    pub fn has_power_relay(&self) -> bool{
+        return self.power.is_some();
+    }
+    pub fn power_cycle(&mut self) -> bool{
+        if self.power.is_some(){
+            self.hold(step::REBOOT);
+        }
+        match self.power{
+            Some(ref mut power) => {
+                log::info!("Power cycling device {}...",self.serial);
+                power.energise();
+                thread::sleep(self.power_settings.off_time);
+                power.release();
+            },
+            None => {
+                log::warn!("Device {} has no power relay; cannot power cycle.",self.serial);
+                return false;
+            }
+        }
+        //Not counted here: recovery power cycles are not part of the test.
+        //A reboot step that power cycles in place of a reboot records it.
+        self.current_state = State::LoginPrompt;
+        self.finish_boot();
+        return true;
+    }
+    //Power cycles the unit if it has gone quiet for longer than allowed.
+    fn recover_if_unresponsive(&mut self) -> bool{
+        let timeout = match self.power_settings.unresponsive_timeout{
+            Some(timeout) if self.power.is_some() => timeout,
+            _ => return false,
+        };
+        let silence = self.usb_tty.time_since_response();
+        if silence < timeout{
+            return false;
+        }
+        log::warn!("Device {} has not responded for {}s; attempting power-cycle recovery.",self.serial,silence.as_secs());
+        self.power_cycle();
+        if !self.wait_for_login_prompt(LOGIN_PROMPT_TIMEOUT){
+            log::error!("Device {} did not recover after power cycle.",self.serial);
+            self.report_failing(true);
+        }
+        return true;
+    }
+    pub fn set_pause_signal(&mut self, pause:PauseSignal) -> &mut Self{
+        self.pause = Some(pause);
+        return self;
+    }
+    pub fn set_shutdown_signal(&mut self, shutdown:ShutdownSignal) -> &mut Self{
+        self.shutdown = Some(shutdown);
+        return self;
+    }
+    pub fn is_stopping(&self) -> bool{
+        return self.shutdown.as_ref().is_some_and(|shutdown| shutdown.is_requested());
+    }
+    //Sleeps between commands, cut short if the run is stopping.
+    pub fn sleep(&self, duration:Duration){
+        match self.shutdown{
+            Some(ref shutdown) => _ = shutdown.sleep(duration),
+            None => thread::sleep(duration),
+        }
+    }
+    //Temp relay released, as the unit should be left when the run stops. The
+    //screen is only brightened from the menus: logging in or waiting on a boot
+    //could outlast the shutdown grace period.
+    pub fn make_safe(&mut self){
+        self.held.clear();
+        self.stop_temp();
+        if matches!(self.current_state, State::DebugMenu | State::LifecycleMenu | State::BrightnessMenu){
+            self.brighten_screen();
+        }
+    }
+    pub fn set_status_board(&mut self, status:Arc<StatusBoard>) -> &mut Self{
+        self.status = Some(status);
+        return self;
+    }
+    pub fn report_failing(&self, failing:bool){
+        if let Some(ref status) = self.status{
+            status.set_failing(&self.get_port_name(),failing);
+        }
+    }
+    //Safe point between test steps: holds here with the temp relay released while paused.
+    //False if the run is stopping, which also ends a pause; nothing more should
+    //then be sent to the unit.
+    pub fn wait_if_paused(&mut self) -> bool{
+        let pause = match self.pause{
+            Some(ref pause) if pause.is_paused() => pause.clone(),
+            _ => return !self.is_stopping(),
+        };
+        self.stop_temp();
+        log::info!("Device {} paused.",self.serial);
+        pause.wait_while_paused();
+        if self.is_stopping(){
+            return false;
+        }
+        log::info!("Device {} resumed.",self.serial);
+        return true;
+    }
     pub fn start_temp(&mut self) -> &mut Self {
         if let Some(ref mut pin) = self.pin {
-            pin.set_high();
+            pin.energise();
         }
         return self;
     }
     pub fn stop_temp(&mut self) -> &mut Self {
         if let Some(ref mut pin) = self.pin {
-            pin.set_low();
+            pin.release();
         }
         return self;
     }
+    //Checks the temp reading follows this unit's relay: off while released and
+    //on while energised. A welded relay shows up as temp reading on while released.
+    pub fn check_temp_relay(&mut self) -> Result<(),String>{
+        let address = match self.get_pin_address(){
+            Some(address) => address,
+            None => return Ok(()),
+        };
+        if self.stop_temp().is_temp_running(){
+            return Err(format!("temp reads on while relay {} is released; relay stuck closed?",address));
+        }
+        let temp_while_energised = self.start_temp().is_temp_running();
+        self.stop_temp();
+        if !temp_while_energised{
+            return Err(format!("temp does not follow relay {}",address));
+        }
+        return Ok(());
+    }
+    //Holds a bp place until the BP is seen to have finished or the cycle ends.
     pub fn start_bp(&mut self) -> &mut Self {
         self.go_to_lifecycle_menu();
-        self.usb_tty.write_to_device(Command::StartBP);
-        _ = self.usb_tty.read_from_device(None);
+        self.hold(step::BP);
+        _ = self.exchange(Command::StartBP);
         return self;
     }
     pub fn darken_screen(&mut self) -> &mut Self {
         self.go_to_brightness_menu();
-        self.usb_tty.write_to_device(Command::BrightnessLow);
-        _ = self.usb_tty.read_from_device(None);
+        _ = self.exchange(Command::BrightnessLow);
         return self;
     }
     pub fn brighten_screen(&mut self) -> &mut Self {
         self.go_to_brightness_menu();
-        self.usb_tty.write_to_device(Command::BrightnessHigh);
-        _ = self.usb_tty.read_from_device(None);
+        _ = self.exchange(Command::BrightnessHigh);
         return self;
     }
     pub fn is_temp_running(&mut self) -> bool {
         self.go_to_lifecycle_menu();
-        self.usb_tty.write_to_device(Command::ReadTemp);
+        let mut response = self.exchange(Command::ReadTemp);
         loop {
-            match self.usb_tty.read_from_device(None){
+            match response{
                 Response::TempSuccess => return true,
                 Response::TempFailed => return false,
-                _ => {},
+                _ => {
+                    if self.recover_if_unresponsive(){
+                        return false;
+                    }
+                },
             }
+            response = self.usb_tty.read_from_device(None);
         }
     }
     pub fn is_bp_running(&mut self) -> bool {
         self.go_to_lifecycle_menu();
-        self.usb_tty.write_to_device(Command::CheckBPState);
+        let mut response = self.exchange(Command::CheckBPState);
         loop { 
-            match self.usb_tty.read_from_device(None){
+            match response{
                 Response::BPOn => return true,
-                Response::BPOff => return false,
+                Response::BPOff => {
+                    self.let_go(step::BP);
+                    return false;
+                },
                 Response::DebugMenuWithContinuedMessage =>{},
                 _ => return false,
             }
+            response = self.usb_tty.read_from_device(None);
+        }
+    }
+    //Sends a command and reads the reply, sending it again while the reply is
+    //one its retry policy puts down to the link rather than the device.
+    fn exchange(&mut self, command:Command) -> Response{
+        let policy = self.command_retries.get(&command).cloned().unwrap_or_default();
+        let mut attempt = 1;
+        loop{
+            self.usb_tty.write_to_device(command);
+            let response = self.usb_tty.read_from_device(None);
+            if !policy.should_retry(attempt,&response){
+                return response;
+            }
+            log::info!("Retrying {:?} on device {} after {:?} (attempt {} of {})",command,self.serial,response,attempt+1,policy.attempts);
+            self.record(COMMAND_RETRIES);
+            self.sleep(policy.get_delay(attempt));
+            attempt += 1;
+        }
+    }
+    pub fn set_coordinator(&mut self, coordinator:Arc<Coordinator>) -> &mut Self{
+        self.coordinator = Some(coordinator);
+        return self;
+    }
+    //Waits for a place to run the operation if it is limited. A place this
+    //unit already holds is not taken twice.
+    //Never waits for a place once the run is stopping.
+    pub fn hold(&mut self, operation:&str){
+        if self.held.contains_key(operation) || self.is_stopping(){
+            return;
+        }
+        let permit = self.coordinator.as_ref().and_then(|coordinator| coordinator.acquire(operation,&self.serial));
+        if let Some(permit) = permit{
+            self.held.insert(operation.to_string(),permit);
+        }
+    }
+    pub fn let_go(&mut self, operation:&str){
+        self.held.remove(operation);
+    }
+    //Keeps a held reboot place until the unit is back at its login prompt or
+    //the boot budget runs out, so the limit covers the boot and not just the command.
+    fn finish_boot(&mut self){
+        if !self.held.contains_key(step::REBOOT){
+            return;
+        }
+        let budget = self.coordinator.as_ref().map(|coordinator| coordinator.get_boot_budget()).unwrap_or_default();
+        if !self.wait_for_login_prompt(budget){
+            log::warn!("Device {} still booting after {}s; giving up its reboot place.",self.serial,budget.as_secs());
+        }
+        self.let_go(step::REBOOT);
+    }
+    //Waits the coordinator's random start-up stagger, if any.
+    pub fn stagger(&self){
+        let delay = match self.coordinator.as_ref(){
+            Some(coordinator) => coordinator.get_stagger_delay(),
+            None => return,
+        };
+        if !delay.is_zero(){
+            log::info!("Device {} starting in {:.1}s",self.serial,delay.as_secs_f64());
+            self.sleep(delay);
         }
     }
+    pub fn set_command_retries(&mut self, command_retries:HashMap<Command,RetryPolicy>) -> &mut Self{
+        self.command_retries = command_retries;
+        return self;
+    }
+    //Reboots by quitting the debug menu, entering it first if need be, so the
+    //unit really reboots wherever it was. Counting it is up to the caller.
     pub fn reboot(&mut self) -> () {
+        if matches!(self.current_state, State::LoginPrompt | State::Shell){
+            self.go_to_debug_menu();
+        }
         self.go_to_login_prompt();
         self.current_state = State::LoginPrompt;
     }
@@ -359,45 +854,672 @@ impl Device{
         }
         else{
             self.go_to_login_prompt();
-            self.reboots +=1;
-            self.save_values();
+            self.record(REBOOTS);
             return true;
         }
     }
     pub fn test_cycle(&mut self, bp_cycles: Option<u64>, temp_cycles: Option<u64>) -> () {
-        let local_bp_cycles: u64 = bp_cycles.unwrap_or(3);
-        let local_temp_cycles: u64 = temp_cycles.unwrap_or(2);
-        self.go_to_login_prompt();
-        thread::sleep(BOOT_TIME);
-        self.go_to_lifecycle_menu();
-        _ = self.usb_tty.read_from_device(Some("["));
-        for _bp_count in 1..=local_bp_cycles{
-            log::info!("Running bp {} on device {} ...",(self.bps+1),self.serial);
-            self.start_bp();
-            let bp_start = self.is_bp_running();
-            log::trace!("{:?}",bp_start);
-            thread::sleep(BP_RUN);
-            let bp_end = self.is_bp_running();
-            log::trace!("{:?}",bp_end);
-            if bp_start != bp_end {
-                self.bps +=1;
-                log::debug!("Increasing bp count to {}",self.bps);
-                self.save_values();
+        self.run_plan(&TestPlan::standard_cycle(bp_cycles.unwrap_or(3),temp_cycles.unwrap_or(2)));
+    }
+    //Runs one cycle of the plan, or the rest of an interrupted one. Any failed
+    //step marks the device as failing until a clean cycle.
+    pub fn run_plan(&mut self, plan:&TestPlan){
+        if self.pin.is_none() && plan.has_step(step::TEMP){
+            log::info!("No relay assigned to device {}; skipping temp tests.",self.serial);
+        }
+        let start = self.resume_at.unwrap_or_default();
+        if !self.start_cycle(){
+            log::info!("Device {} stopped before cycle {}",self.serial,self.cycles+1);
+            return;
+        }
+        let mut failed = false;
+        'steps: for (index, planned) in plan.steps.iter().enumerate().skip(start.step){
+            let first_repeat = match index == start.step{
+                true => start.repeat,
+                false => 0,
+            };
+            for repeat in first_repeat..planned.repeat{
+                //A step cut short is neither counted nor checkpointed, so resume runs it again.
+                let passed = self.wait_if_paused() && self.run_planned_step(planned);
+                if self.is_stopping(){
+                    log::info!("Device {} stopped partway through cycle {}",self.serial,self.cycles+1);
+                    return;
+                }
+                if !passed{
+                    log::warn!("Step {} failed on device {}",planned.step.get_name(),self.serial);
+                    failed = true;
+                }
+                self.checkpoint(Some(CyclePosition { step: index, repeat: repeat+1 }));
+                self.note_result(planned.step.get_name(),passed);
+                if self.is_quarantine_due(){
+                    log::warn!("Device {} has failed too often; abandoning cycle {}",self.serial,self.cycles+1);
+                    break 'steps;
+                }
             }
         }
-        for _temp_count in 1..=local_temp_cycles{
-            log::info!("Running temp {} on device {} ...",(self.temps+1),self.serial);
-            let temp_start = self.start_temp().is_temp_running();
-            let temp_end = self.stop_temp().is_temp_running();
-            if temp_start != temp_end {
-                self.temps +=1;
-                log::debug!("Increasing temp count to {}",self.temps);
-                self.save_values();
+        self.finish_cycle(failed);
+    }
+    //Shared by plans and scripts: every cycle starts from the login prompt.
+    //False, having sent nothing, if the run is stopping.
+    pub fn start_cycle(&mut self) -> bool{
+        let position = self.resume_at.take().unwrap_or_default();
+        self.checkpoint(Some(position));
+        if !self.wait_if_paused(){
+            return false;
+        }
+        self.recover_if_unresponsive();
+        self.go_to_login_prompt();
+        return true;
+    }
+    pub fn finish_cycle(&mut self, failed:bool){
+        self.held.clear();
+        self.report_failing(failed);
+        self.cycles += 1;
+        self.checkpoint(None);
+    }
+    pub fn get_cycles(&self) -> u64{
+        return self.cycles;
+    }
+    pub fn set_session(&mut self, session:Arc<Session>) -> &mut Self{
+        self.session = Some(session);
+        return self;
+    }
+    pub fn get_progress(&self) -> DeviceProgress{
+        return DeviceProgress {
+            serial: self.serial.clone(),
+            cycles: self.cycles,
+            counts: self.counts.clone(),
+            quarantined: self.quarantined,
+            position: None,
+        };
+    }
+    pub fn set_failure_policy(&mut self, policy:FailurePolicy) -> &mut Self{
+        self.failure_policy = policy;
+        return self;
+    }
+    //Tracks failures for the failure policy. Scripts report once per cycle.
+    pub fn note_result(&mut self, step_name:&str, passed:bool){
+        if passed{
+            self.consecutive_failures = 0;
+            return;
+        }
+        self.consecutive_failures += 1;
+        if step_name == step::REBOOT{
+            self.failed_reboots += 1;
+        }
+    }
+    fn get_failure_reason(&self) -> Option<String>{
+        let policy = &self.failure_policy;
+        if policy.consecutive_failures > 0 && self.consecutive_failures >= policy.consecutive_failures{
+            return Some(format!("{} failed steps in a row",self.consecutive_failures));
+        }
+        if policy.failed_reboots > 0 && self.failed_reboots >= policy.failed_reboots{
+            return Some(format!("{} failed reboots",self.failed_reboots));
+        }
+        return None;
+    }
+    pub fn is_quarantine_due(&self) -> bool{
+        return self.get_failure_reason().is_some();
+    }
+    //The one chance a failing unit gets: reopen its port, power cycle it if
+    //possible and wait for a login prompt. False if it was already had.
+    pub fn attempt_recovery(&mut self) -> bool{
+        if self.recovery_attempted{
+            return false;
+        }
+        self.recovery_attempted = true;
+        log::warn!("Device {} hit its failure limit ({}); attempting recovery.",self.serial,self.get_failure_reason().unwrap_or_default());
+        self.stop_temp();
+        let mut connected = self.usb_tty.reconnect();
+        if self.power.is_some(){
+            self.power_cycle();
+            //A powered-down unit can take its serial adapter with it.
+            if !connected{
+                connected = self.usb_tty.reconnect();
             }
         }
-        log::info!("Rebooting {} for the {}th time",self.serial, self.reboots);
-        self.reboot();
-        self.reboots += 1;
+        if !connected{
+            log::error!("Device {} could not be reconnected on {}.",self.serial,self.get_port_name());
+            return false;
+        }
+        if !self.wait_for_login_prompt(LOGIN_PROMPT_TIMEOUT){
+            log::error!("Device {} did not recover.",self.serial);
+            return false;
+        }
+        log::info!("Device {} recovered; carrying on.",self.serial);
+        self.current_state = State::LoginPrompt;
+        self.consecutive_failures = 0;
+        self.failed_reboots = 0;
+        return true;
+    }
+    //Takes the unit out of the run: temp relay released, failure shown on the
+    //lights, and its state and recent serial traffic written out for the operator.
+    pub fn quarantine(&mut self){
+        let reason = self.get_failure_reason().unwrap_or("recovery failed".to_string());
+        self.stop_temp();
+        self.held.clear();
+        self.report_failing(true);
+        self.quarantined = true;
+        self.checkpoint(None);
+        let report_path = quarantine_path(&self.serial);
+        let mut report = format!("Quarantined: {}\nReason: {}\nPort: {}\nState: {:?}\nCycles this run: {}\n",
+            Local::now().to_rfc3339(),reason,self.get_port_name(),self.current_state,self.cycles);
+        report.push_str(&self.counts.render());
+        report.push_str("\nLast serial traffic:\n");
+        for line in self.usb_tty.get_transcript(){
+            report.push_str(&line);
+            report.push('\n');
+        }
+        if let Err(error) = fs::write(&report_path,report){
+            log::warn!("Unable to write {}",report_path);
+            log::debug!("{}",error);
+        }
+        log::error!("Device {} on {} is quarantined: {}. Details are in {}",self.serial,self.get_port_name(),reason,report_path);
+    }
+    fn checkpoint(&self, position:Option<CyclePosition>){
+        if let Some(ref session) = self.session{
+            session.update(DeviceProgress { position, ..self.get_progress() });
+        }
+    }
+    //Puts the totals back as they were at the last checkpoint and picks the
+    //interrupted cycle up from there.
+    pub fn resume_from(&mut self, progress:&DeviceProgress){
+        self.cycles = progress.cycles;
+        self.counts = progress.counts.clone();
+        self.save_values();
+        self.resume_at = progress.position;
+        match progress.position{
+            Some(_) => log::info!("Resuming device {} partway through cycle {}",self.serial,self.cycles+1),
+            None => log::info!("Resuming device {} after {} cycle(s)",self.serial,self.cycles),
+        }
+    }
+    //Adds one to a count and saves it straight away; returns the new total.
+    pub fn record(&mut self, label:&str) -> u64{
+        let value = self.counts.increment(label);
+        log::debug!("Increasing {} to {}",label,value);
+        self.save_values();
+        return value;
+    }
+    pub fn get_count(&self, label:&str) -> u64{
+        return self.counts.get(label);
+    }
+    //A unit whose targets were raised since it was retired loses its mark and carries on.
+    pub fn set_targets(&mut self, targets:Targets) -> &mut Self{
+        self.targets = targets;
+        if !self.is_complete() && self.get_count(TARGETS_MET) > 0{
+            self.counts.remove(TARGETS_MET);
+            self.save_values();
+        }
+        return self;
+    }
+    pub fn is_complete(&self) -> bool{
+        return self.targets.is_met(&self.counts);
+    }
+    //Targets this unit can never meet on the plan, with the reason for each.
+    pub fn get_unreachable_targets(&self, plan:&TestPlan) -> Vec<(String,&'static str)>{
+        let mut unreachable = Vec::new();
+        for (label, _) in self.targets.iter(){
+            let reason = match label{
+                BP_TESTS if !plan.has_step(step::BP) => "the plan has no bp step",
+                TEMP_TESTS if !plan.has_step(step::TEMP) => "the plan has no temp step",
+                TEMP_TESTS if self.pin.is_none() => "it has no temp relay",
+                REBOOTS | HARD_REBOOTS if !plan.has_step(step::REBOOT) => "the plan has no reboot step",
+                HARD_REBOOTS if self.power.is_none() => "it has no power relay",
+                HARD_REBOOTS if self.power_settings.hard_reboot_every == 0 => "hard_reboot_every is not set",
+                _ => continue,
+            };
+            unreachable.push((label.to_string(),reason));
+        }
+        return unreachable;
+    }
+    pub fn get_remaining(&self) -> Vec<(String,u64)>{
+        return self.targets.get_remaining(&self.counts);
+    }
+    //Leaves a unit that has met its targets with the temp relay released and
+    //the screen bright, and marks it complete in its output file.
+    pub fn retire(&mut self){
+        self.held.clear();
+        self.stop_temp();
+        self.brighten_screen();
+        self.report_failing(false);
+        if let Some(ref status) = self.status{
+            status.set_retired(&self.get_port_name());
+        }
+        self.counts.set(TARGETS_MET,1);
         self.save_values();
+        log::info!("Device {} has met its targets and is retired.",self.serial);
+    }
+    pub fn enter_lifecycle_menu(&mut self){
+        if self.current_state != State::LifecycleMenu{
+            self.go_to_lifecycle_menu();
+            _ = self.usb_tty.read_from_device(Some("["));
+        }
+    }
+    //True when this cycle's reboot should be a power cycle instead.
+    pub fn is_power_cycle_due(&self) -> bool{
+        let every = self.power_settings.hard_reboot_every;
+        return every > 0 && self.power.is_some() && (self.cycles+1).is_multiple_of(every);
+    }
+    //Runs a step from a plan, trying again as its retry policy allows. Only
+    //the last attempt's result counts.
+    fn run_planned_step(&mut self, planned:&PlannedStep) -> bool{
+        let mut attempt = 1;
+        loop{
+            let passed = self.run_step(planned.step.as_ref());
+            if passed || self.is_stopping() || attempt >= planned.retry.attempts{
+                return passed;
+            }
+            log::info!("Retrying step {} on device {} (attempt {} of {})",planned.step.get_name(),self.serial,attempt+1,planned.retry.attempts);
+            self.record(STEP_RETRIES);
+            self.sleep(planned.retry.get_delay(attempt));
+            attempt += 1;
+        }
+    }
+    //Prepares, executes and evaluates one step, recording its count on a pass.
+    //A step that declines to prepare is skipped and counts as a pass.
+    pub fn run_step(&mut self, step:&dyn TestStep) -> bool{
+        //Held from prepare through execute; evaluating needs no place.
+        let held_here = !step.holds_own_place();
+        if held_here{
+            self.hold(step.get_name());
+        }
+        if !step.prepare(self){
+            if held_here{
+                self.let_go(step.get_name());
+            }
+            return true;
+        }
+        let observation = step.execute(self);
+        if held_here{
+            self.let_go(step.get_name());
+        }
+        log::trace!("{} observed {:?}",step.get_name(),observation);
+        if self.is_stopping(){
+            return false;
+        }
+        if !step.evaluate(&observation){
+            return false;
+        }
+        if let Some(label) = step.get_record(&observation){
+            self.record(label);
+        }
+        return true;
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::gpio_facade::MockBackend;
+    use std::{io::Read, net::TcpListener, sync::mpsc};
+    use crate::test_unit::{no_relays, Mode, Temp, TestUnit};
+    use crate::tty::SerialSettings;
+    use crate::step::{BpStep, Observation, RebootStep};
+
+    const TEMP_RELAY:u8 = 4;
+    const POWER_RELAY:u8 = 20;
+
+    //A unit on its own relay, with its temp reading set by the test.
+    fn unit_on_relay(serial:&str, temp:impl FnOnce(&MockBackend) -> Temp) -> (TestUnit, Device){
+        let backend = MockBackend::new();
+        let unit = TestUnit::start(serial);
+        unit.state().temp = temp(&backend);
+        let relays = RelayAllocator::new(Arc::new(backend),RelaySettings { addresses: vec![TEMP_RELAY], ..Default::default() });
+        let mut device = unit.device_with_relays(relays);
+        device.set_pin_address(TEMP_RELAY);
+        return (unit, device);
+    }
+
+    #[test]
+    fn temp_relay_check_passes_when_temp_follows_the_relay(){
+        let (_unit, mut device) = unit_on_relay("relay-good",|backend| Temp::Relay(backend.clone(),TEMP_RELAY));
+        assert_eq!(device.check_temp_relay(),Ok(()));
+        assert!(!device.is_temp_running());
+    }
+
+    #[test]
+    fn temp_relay_check_finds_a_welded_relay(){
+        let (_unit, mut device) = unit_on_relay("relay-welded",|_| Temp::Fixed(36));
+        assert!(device.check_temp_relay().unwrap_err().contains("stuck closed"));
+    }
+
+    #[test]
+    fn temp_relay_check_finds_a_relay_that_does_nothing(){
+        let (_unit, mut device) = unit_on_relay("relay-open",|_| Temp::Fixed(0));
+        assert!(device.check_temp_relay().unwrap_err().contains("does not follow"));
+    }
+
+    #[test]
+    fn temp_relay_check_passes_a_unit_without_a_relay(){
+        let unit = TestUnit::start("relay-none");
+        let mut device = unit.device();
+        assert_eq!(device.check_temp_relay(),Ok(()));
+        assert!(unit.get_received().is_empty());
+    }
+
+    //A unit whose power is cut by its own relay.
+    fn unit_on_power_relay(serial:&str, settings:PowerSettings) -> (TestUnit, MockBackend, Device){
+        let backend = MockBackend::new();
+        let unit = TestUnit::start(serial);
+        unit.state().power = Some((backend.clone(),POWER_RELAY));
+        let power_relays = RelayAllocator::new(Arc::new(backend.clone()),RelaySettings { addresses: vec![POWER_RELAY], ..Default::default() });
+        let mut device = unit.device();
+        device.set_power_relay(power_relays.lease(POWER_RELAY).unwrap(),settings);
+        return (unit, backend, device);
+    }
+
+    //How many times power was cut, checking it was restored after the last.
+    fn power_cuts(backend:&MockBackend) -> usize{
+        assert!(!backend.is_high(POWER_RELAY));
+        return backend.get_changes().iter().filter(|change| change.address == POWER_RELAY && change.high).count();
+    }
+
+    fn quick_power(hard_reboot_every:u64, unresponsive_timeout:Option<Duration>) -> PowerSettings{
+        return PowerSettings { off_time: Duration::from_millis(10), hard_reboot_every, unresponsive_timeout };
+    }
+
+    #[test]
+    fn power_cycle_needs_a_power_relay(){
+        let unit = TestUnit::start("power-none");
+        let mut device = unit.device();
+        assert!(!device.power_cycle());
+        assert!(unit.get_received().is_empty());
+    }
+
+    #[test]
+    fn power_cycle_cuts_power_then_restores_it(){
+        let (unit, backend, mut device) = unit_on_power_relay("power-cycle",quick_power(0,None));
+        unit.state().mode = Mode::Menu;
+        assert!(device.power_cycle());
+        assert_eq!(power_cuts(&backend),1);
+        assert!(device.wait_for_login_prompt(Duration::from_secs(5)));
+        assert_eq!(unit.state().power_cycles,1);
+        assert_eq!(device.get_count(HARD_REBOOTS),0);
+        assert_eq!(device.get_count(REBOOTS),0);
+    }
+
+    #[test]
+    fn every_nth_reboot_is_a_power_cycle_counted_apart_from_reboots(){
+        let (unit, backend, mut device) = unit_on_power_relay("power-every",quick_power(2,None));
+        let plan = TestPlan { steps: vec![PlannedStep::new(Arc::new(RebootStep { timeout_s: 5 }),1)], ..Default::default() };
+        device.run_plan(&plan);
+        device.run_plan(&plan);
+        assert_eq!(device.get_count(REBOOTS),1);
+        assert_eq!(device.get_count(HARD_REBOOTS),1);
+        assert_eq!(unit.state().reboots,1);
+        assert_eq!(unit.state().power_cycles,1);
+        assert_eq!(power_cuts(&backend),1);
+    }
+
+    #[test]
+    fn a_silent_unit_is_power_cycled_back_without_counting_it(){
+        let (unit, backend, mut device) = unit_on_power_relay("power-recover",quick_power(0,Some(Duration::from_millis(100))));
+        unit.state().silent = true;
+        thread::sleep(Duration::from_millis(150));
+        assert!(device.recover_if_unresponsive());
+        assert_eq!(power_cuts(&backend),1);
+        assert!(!unit.state().silent);
+        assert_eq!(device.get_count(HARD_REBOOTS),0);
+        assert_eq!(device.get_count(REBOOTS),0);
+    }
+
+    #[test]
+    fn a_unit_within_its_timeout_is_left_alone(){
+        let (_unit, backend, mut device) = unit_on_power_relay("power-quiet",quick_power(0,Some(Duration::from_secs(60))));
+        assert!(!device.recover_if_unresponsive());
+        assert_eq!(power_cuts(&backend),0);
+    }
+
+    #[test]
+    fn making_safe_at_the_login_prompt_sends_nothing(){
+        let (unit, mut device) = unit_on_relay("safe-login",|_| Temp::Fixed(0));
+        device.start_temp();
+        device.make_safe();
+        assert!(!device.pin.as_ref().unwrap().is_energised());
+        assert!(unit.get_received().is_empty());
+    }
+
+    #[test]
+    fn making_safe_in_the_menus_brightens_the_screen(){
+        let (unit, mut device) = unit_on_relay("safe-menu",|_| Temp::Fixed(0));
+        device.start_temp();
+        assert!(!device.is_temp_running());
+        let sent_before = unit.get_received().len();
+        device.make_safe();
+        assert!(!device.pin.as_ref().unwrap().is_energised());
+        assert!(unit.get_received().len() > sent_before);
+        assert_eq!(unit.state().mode,Mode::Menu);
+    }
+
+    fn bp_limited_to_one() -> Arc<Coordinator>{
+        return Arc::new(Coordinator::new(&HashMap::from([(step::BP.to_string(),1)]),Duration::ZERO,Duration::ZERO));
+    }
+
+    #[test]
+    fn a_bp_keeps_its_place_until_it_is_seen_to_finish(){
+        let unit = TestUnit::start("bp-place-kept");
+        unit.state().bp_checks = 3;
+        let mut device = unit.device();
+        device.set_coordinator(bp_limited_to_one());
+        assert!(!device.run_step(&BpStep { run_s: 0 }));
+        assert!(device.held.contains_key(step::BP));
+        assert!(device.is_bp_running());
+        assert!(device.held.contains_key(step::BP));
+        assert!(!device.is_bp_running());
+        assert!(!device.held.contains_key(step::BP));
+    }
+
+    #[test]
+    fn a_bp_that_finishes_within_its_step_gives_its_place_back(){
+        let unit = TestUnit::start("bp-place-freed");
+        let mut device = unit.device();
+        device.set_coordinator(bp_limited_to_one());
+        assert!(device.run_step(&BpStep { run_s: 0 }));
+        assert!(!device.held.contains_key(step::BP));
+        assert_eq!(device.get_count(BP_TESTS),1);
+    }
+
+    #[cfg(not(feature = "hardware-gpio"))]
+    #[test]
+    fn without_hardware_gpio_a_device_has_no_relays_to_take(){
+        let unit = TestUnit::start("no-gpio");
+        let device = Device::new(unit.open(),Some(Response::LoginPrompt)).unwrap();
+        assert!(device.relays.get_addresses().is_empty());
+        assert!(device.relays.check_lines().is_empty());
+    }
+
+    fn fail_steps(device:&mut Device, step_name:&str, count:u64){
+        for _ in 0..count{
+            device.note_result(step_name,false);
+        }
+    }
+
+    #[test]
+    fn failed_steps_in_a_row_make_quarantine_due(){
+        let unit = TestUnit::start("quarantine-row");
+        let mut device = unit.device();
+        device.set_failure_policy(FailurePolicy { consecutive_failures: 3, failed_reboots: 0 });
+        fail_steps(&mut device,step::BP,2);
+        assert!(!device.is_quarantine_due());
+        device.note_result(step::TEMP,true);
+        fail_steps(&mut device,step::BP,2);
+        assert!(!device.is_quarantine_due());
+        fail_steps(&mut device,step::TEMP,1);
+        assert_eq!(device.get_failure_reason().as_deref(),Some("3 failed steps in a row"));
+    }
+
+    #[test]
+    fn failed_reboots_count_over_the_whole_run(){
+        let unit = TestUnit::start("quarantine-reboots");
+        let mut device = unit.device();
+        device.set_failure_policy(FailurePolicy { consecutive_failures: 0, failed_reboots: 2 });
+        fail_steps(&mut device,step::REBOOT,1);
+        device.note_result(step::REBOOT,true);
+        fail_steps(&mut device,step::BP,10);
+        assert!(!device.is_quarantine_due());
+        fail_steps(&mut device,step::REBOOT,1);
+        assert_eq!(device.get_failure_reason().as_deref(),Some("2 failed reboots"));
+    }
+
+    #[test]
+    fn a_unit_gets_one_recovery_attempt(){
+        let unit = TestUnit::start("quarantine-recover");
+        let mut device = unit.device();
+        device.set_failure_policy(FailurePolicy { consecutive_failures: 2, failed_reboots: 0 });
+        fail_steps(&mut device,step::BP,2);
+        assert!(device.attempt_recovery());
+        assert!(!device.is_quarantine_due());
+        fail_steps(&mut device,step::BP,2);
+        assert!(device.is_quarantine_due());
+        assert!(!device.attempt_recovery());
+        assert!(device.is_quarantine_due());
+    }
+
+    #[test]
+    fn recovery_power_cycles_a_hung_unit(){
+        let (unit, backend, mut device) = unit_on_power_relay("quarantine-hung",quick_power(0,None));
+        device.set_failure_policy(FailurePolicy { consecutive_failures: 1, failed_reboots: 0 });
+        fail_steps(&mut device,step::BP,1);
+        unit.state().silent = true;
+        assert!(device.attempt_recovery());
+        assert_eq!(power_cuts(&backend),1);
+        assert_eq!(device.get_count(HARD_REBOOTS),0);
+    }
+
+    #[test]
+    fn quarantine_writes_what_the_operator_needs(){
+        let unit = TestUnit::start("quarantine-log");
+        let mut device = unit.device();
+        device.set_failure_policy(FailurePolicy { consecutive_failures: 1, failed_reboots: 0 });
+        assert!(device.run_shell_command("uptime",Duration::from_secs(5)).is_some());
+        fail_steps(&mut device,step::COMMAND,1);
+        device.quarantine();
+        assert!(device.get_progress().quarantined);
+        let path = quarantine_path("quarantine-log");
+        assert!(path.ends_with("output/quarantine-log-quarantine.log"));
+        let report = fs::read_to_string(&path).unwrap();
+        assert!(report.contains("Reason: 1 failed steps in a row\n"),"{}",report);
+        assert!(report.contains(&format!("Port: {}\n",device.get_port_name())),"{}",report);
+        assert!(report.contains("Last serial traffic:\n"),"{}",report);
+        assert!(report.contains("uptime"),"{}",report);
+    }
+
+    //Records a count every time it runs, and nothing else.
+    #[derive(Debug)]
+    struct Tally(&'static str);
+
+    impl TestStep for Tally{
+        fn get_name(&self) -> &str{
+            return "tally";
+        }
+        fn execute(&self, _device:&mut Device) -> Observation{
+            return Observation::Completed(true);
+        }
+        fn evaluate(&self, observation:&Observation) -> bool{
+            return *observation == Observation::Completed(true);
+        }
+        fn get_record(&self, _observation:&Observation) -> Option<&str>{
+            return Some(self.0);
+        }
+    }
+
+    fn tally_plan() -> TestPlan{
+        return TestPlan { steps: vec![PlannedStep::new(Arc::new(Tally("A")),3),PlannedStep::new(Arc::new(Tally("B")),2)], ..Default::default() };
+    }
+
+    fn resume_at(device:&mut Device, a:u64, b:u64, position:CyclePosition){
+        let mut counts = Counts::default();
+        counts.set("A",a);
+        counts.set("B",b);
+        let serial = device.get_serial().to_string();
+        device.resume_from(&DeviceProgress { serial, cycles: 4, counts, quarantined: false, position: Some(position) });
+    }
+
+    #[test]
+    fn resuming_partway_through_a_step_runs_only_its_remaining_repeats(){
+        let unit = TestUnit::start("resume-repeat");
+        let mut device = unit.device();
+        resume_at(&mut device,13,8,CyclePosition { step: 0, repeat: 1 });
+        device.run_plan(&tally_plan());
+        assert_eq!((device.get_count("A"),device.get_count("B"),device.get_cycles()),(15,10,5));
+        device.run_plan(&tally_plan());
+        assert_eq!((device.get_count("A"),device.get_count("B"),device.get_cycles()),(18,12,6));
+    }
+
+    #[test]
+    fn resuming_at_a_later_step_skips_the_steps_already_done(){
+        let unit = TestUnit::start("resume-step");
+        let mut device = unit.device();
+        resume_at(&mut device,15,9,CyclePosition { step: 1, repeat: 1 });
+        device.run_plan(&tally_plan());
+        assert_eq!((device.get_count("A"),device.get_count("B"),device.get_cycles()),(15,10,5));
+    }
+
+    //A unit mid-boot on a bare console: it prints its bootloader countdown,
+    //then its kernel and login prompt, unless a key arrives during the countdown.
+    fn booting_console() -> (String, mpsc::Receiver<bool>){
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let location = format!("tcp://{}",listener.local_addr().unwrap());
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move ||{
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(b"U-Boot 2020.04\nHit any key to stop autoboot:  1").unwrap();
+            stream.set_read_timeout(Some(Duration::from_millis(400))).unwrap();
+            let interrupted = stream.read(&mut [0u8; 64]).is_ok_and(|count| count > 0);
+            _ = sender.send(interrupted);
+            if interrupted{
+                _ = stream.write_all(b"\n=> ");
+                return;
+            }
+            stream.write_all(b"\nStarting kernel ...\n[    0.000000] Booting Linux\n").unwrap();
+            thread::sleep(Duration::from_millis(200));
+            stream.write_all(b"\nunit login:").unwrap();
+            thread::sleep(Duration::from_millis(500));
+        });
+        return (location, receiver);
+    }
+
+    #[test]
+    fn waiting_for_the_login_prompt_leaves_a_booting_unit_alone(){
+        let (location, interrupted) = booting_console();
+        let settings = SerialSettings { timeout: Duration::from_millis(50), lock_directory: None, ..Default::default() };
+        let tty = TTY::new_with_settings(&location,&settings).unwrap();
+        let mut device = Device::new_with_relays(tty,Some(Response::Rebooting),no_relays()).unwrap();
+        assert!(device.wait_for_login_prompt(Duration::from_secs(30)));
+        assert!(!interrupted.recv().unwrap());
+    }
+
+    #[test]
+    fn a_silent_unit_is_nudged_for_its_login_prompt(){
+        let unit = TestUnit::start("login-nudge");
+        let mut device = unit.device();
+        assert!(device.wait_for_login_prompt(Duration::from_secs(5)));
+        assert_eq!(unit.get_received(),vec!["\n"]);
+    }
+
+    #[test]
+    fn counts_start_with_every_built_in_label(){
+        let counts = Counts::default();
+        assert_eq!(counts.iter().map(|(label, _)| label).collect::<Vec<_>>(),COUNT_LABELS.to_vec());
+        assert!(counts.iter().all(|(_, value)| value == 0));
+    }
+
+    #[test]
+    fn counts_round_trip_through_the_output_file(){
+        let mut counts = Counts::default();
+        counts.set(REBOOTS,12);
+        counts.increment(BP_TESTS);
+        counts.set("Brightness checks",3);
+        let rendered = counts.render();
+        assert_eq!(Counts::parse(&rendered),counts);
+        assert_eq!(Counts::parse(&rendered).render(),rendered);
+    }
+
+    #[test]
+    fn counts_skip_lines_they_cannot_read(){
+        let counts = Counts::parse("Reboots: 7\nnot a count\nSuccessful BP tests: lots\n\n");
+        assert_eq!(counts.get(REBOOTS),7);
+        assert_eq!(counts.get(BP_TESTS),0);
+        assert_eq!(counts.get("not a count"),0);
     }
 }
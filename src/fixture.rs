@@ -0,0 +1,123 @@
+use std::{collections::HashMap, fs, path::Path};
+use serde::{Deserialize, Serialize};
+
+pub const DEFAULT_FIXTURE_FILE: &str = "fixture.toml";
+const SLOT_PREFIX: &str = "slot-";
+
+//One position on the rack: the stable port it is cabled to, the relay that
+//drives its temp probe, and the serial of the unit last enrolled there.
+//The power relay and status LED are optional and only ever set by hand.
+#[derive(Clone,Debug,Deserialize,Serialize,PartialEq)]
+pub struct Slot{
+    pub name: String,
+    pub port: String,
+    pub relay: Option<u8>,
+    pub serial: String,
+    #[serde(default)]
+    pub power_relay: Option<u8>,
+    #[serde(default)]
+    pub status_led: Option<u8>,
+}
+
+#[derive(Clone,Debug,Default,Deserialize,Serialize)]
+pub struct FixtureMap{
+    #[serde(default)]
+    pub slots: Vec<Slot>,
+}
+
+impl FixtureMap{
+    //Ok(None) when there is no fixture file yet.
+    pub fn load(path:&str) -> Result<Option<Self>,String>{
+        if !Path::new(path).is_file(){
+            return Ok(None);
+        }
+        let contents = fs::read_to_string(path).map_err(|error| format!("Unable to read fixture file {}: {}",path,error))?;
+        let map = toml::from_str(&contents).map_err(|error| format!("Unable to parse fixture file {}: {}",path,error))?;
+        return Ok(Some(map));
+    }
+
+    pub fn save(&self, path:&str) -> Result<(),String>{
+        let contents = toml::to_string(self).map_err(|error| format!("Unable to serialise fixture map: {}",error))?;
+        //Write-then-rename so a crash never leaves a half-written map behind.
+        let temp_path = path.to_owned() + ".tmp";
+        fs::write(&temp_path, contents).map_err(|error| format!("Unable to write fixture file {}: {}",temp_path,error))?;
+        return fs::rename(&temp_path, path).map_err(|error| format!("Unable to write fixture file {}: {}",path,error));
+    }
+
+    pub fn slot_for_port(&self, port:&str) -> Option<&Slot>{
+        return self.slots.iter().find(|slot| slot.port == port);
+    }
+
+    pub fn get_power_relays(&self) -> Vec<u8>{
+        return self.slots.iter().filter_map(|slot| slot.power_relay).collect();
+    }
+
+    //Port to LED address, for the slots that have one.
+    pub fn get_status_leds(&self) -> HashMap<String,u8>{
+        return self.slots.iter().filter_map(|slot| Some((slot.port.clone(),slot.status_led?))).collect();
+    }
+
+    pub fn slot_for_serial(&self, serial:&str) -> Option<&Slot>{
+        return self.slots.iter().find(|slot| slot.serial == serial);
+    }
+
+    //Records a unit on a port, keeping the slot's name if the port is already known.
+    pub fn record(&mut self, port:&str, relay:Option<u8>, serial:&str){
+        for slot in self.slots.iter_mut().filter(|slot| slot.port != port && slot.serial == serial){
+            slot.serial = String::new();
+        }
+        if let Some(slot) = self.slots.iter_mut().find(|slot| slot.port == port){
+            slot.relay = relay;
+            slot.serial = serial.to_string();
+            return;
+        }
+        let mut index = self.slots.len() + 1;
+        while self.slots.iter().any(|slot| slot.name == format!("{}{}",SLOT_PREFIX,index)){
+            index += 1;
+        }
+        self.slots.push(Slot {
+            name: format!("{}{}",SLOT_PREFIX,index),
+            port: port.to_string(),
+            relay,
+            serial: serial.to_string(),
+            power_relay: None,
+            status_led: None,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn slot(name:&str, port:&str, relay:Option<u8>, serial:&str) -> Slot{
+        return Slot { name: name.to_string(), port: port.to_string(), relay, serial: serial.to_string(), power_relay: None, status_led: None };
+    }
+
+    #[test]
+    fn a_serial_moved_to_another_slot_leaves_its_old_slot(){
+        let mut fixture = FixtureMap { slots: vec![slot("slot-1","port-a",Some(1),"A1"),slot("slot-2","port-b",Some(2),"B2")] };
+        fixture.record("port-b",Some(2),"A1");
+        assert_eq!(fixture.slots[0].serial,"");
+        assert_eq!(fixture.slots[0].relay,Some(1));
+        assert_eq!(fixture.slots[1].serial,"A1");
+        assert_eq!(fixture.slot_for_serial("A1").map(|slot| slot.name.as_str()),Some("slot-2"));
+    }
+
+    #[test]
+    fn a_new_port_gets_the_next_unused_slot_name(){
+        let mut fixture = FixtureMap { slots: vec![slot("slot-2","port-a",Some(1),"A1")] };
+        fixture.record("port-b",Some(2),"B2");
+        fixture.record("port-c",None,"C3");
+        let names:Vec<&str> = fixture.slots.iter().map(|slot| slot.name.as_str()).collect();
+        assert_eq!(names,vec!["slot-2","slot-3","slot-4"]);
+        assert_eq!(fixture.slots[2].relay,None);
+    }
+
+    #[test]
+    fn recording_a_known_port_keeps_the_fields_entered_by_hand(){
+        let mut fixture = FixtureMap { slots: vec![Slot { power_relay: Some(20), status_led: Some(21), ..slot("bench","port-a",Some(1),"A1") }] };
+        fixture.record("port-a",Some(3),"Z9");
+        assert_eq!(fixture.slots,vec![Slot { power_relay: Some(20), status_led: Some(21), ..slot("bench","port-a",Some(3),"Z9") }]);
+    }
+}
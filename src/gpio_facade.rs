@@ -1,45 +1,650 @@
-use rppal::gpio::Gpio;
-
-const RELAY_ADDRESSES: [u8;10] = [4,5,6,12,13,17,18,19,20,26];
-
-pub struct GpioPins{
-    unassigned_addresses:Vec<u8>
-}
-
-impl GpioPins{
-    pub fn new() -> Self {
-        let mut output = Self { unassigned_addresses:Vec::new() };
-        for pin in RELAY_ADDRESSES.iter(){
-            let possible_gpio = Gpio::new();
-            match possible_gpio{
-                Ok(gpio_object) =>{
-                    let temp = gpio_object.get(pin.clone());
-                    match temp{
-                        Ok(pin_object)=>{
-                            _ = pin_object.into_output_low();
-                            output.unassigned_addresses.push(*pin);
-                        },
-                        Err(error) => {
-                            log::warn!("Pin unavailable!");
-                            log::debug!("{}",error);
-                        }
-                    }
+use std::{fmt::Debug, sync::{Arc, Mutex, MutexGuard, Weak}, time::{Duration, Instant}, collections::{HashMap, HashSet}, thread};
+#[cfg(feature = "hardware-gpio")]
+use rppal::gpio::{Gpio, OutputPin, InputPin};
+use gpio_cdev::{Chip, LineHandle, LineRequestFlags};
+
+pub const RELAY_ADDRESSES: [u8;10] = [4,5,6,12,13,17,18,19,20,26];
+pub const DEFAULT_GPIO_CHIP: &str = "/dev/gpiochip0";
+#[cfg(feature = "hardware-gpio")]
+pub const DEFAULT_GPIO_BACKEND: &str = "rppal";
+#[cfg(not(feature = "hardware-gpio"))]
+pub const DEFAULT_GPIO_BACKEND: &str = "mock";
+const CDEV_CONSUMER: &str = "seymour";
+
+//A single GPIO line driven as an output, at the physical level (no polarity).
+pub trait OutputLine: Send + Debug{
+    fn set_high(&mut self);
+    fn set_low(&mut self);
+    fn is_set_high(&self) -> bool;
+    fn get_address(&self) -> u8;
+}
+
+//A single GPIO line read as an input.
+pub trait InputLine: Send + Debug{
+    fn is_high(&self) -> bool;
+    fn get_address(&self) -> u8;
+}
+
+//Somewhere lines can be requested from.
+pub trait GpioBackend: Send + Sync + Debug{
+    fn output(&self, address:u8, initial_high:bool) -> Result<Box<dyn OutputLine>,String>;
+    //pull_up is a request; backends that cannot bias lines ignore it.
+    fn input(&self, address:u8, pull_up:bool) -> Result<Box<dyn InputLine>,String>;
+}
+
+#[cfg(feature = "hardware-gpio")]
+#[derive(Debug)]
+pub struct RppalBackend{
+    gpio: Gpio,
+}
+
+#[cfg(feature = "hardware-gpio")]
+impl RppalBackend{
+    pub fn new() -> Result<Self,String>{
+        match Gpio::new(){
+            Ok(gpio) => return Ok(RppalBackend { gpio }),
+            Err(error) => {
+                log::warn!("Unable to open GPIO!");
+                log::debug!("{}",error);
+                return Err("Failed GPIO init".to_string());
+            }
+        }
+    }
+}
+
+#[cfg(feature = "hardware-gpio")]
+impl GpioBackend for RppalBackend{
+    fn output(&self, address:u8, initial_high:bool) -> Result<Box<dyn OutputLine>,String>{
+        let pin = self.gpio.get(address).map_err(|error| error.to_string())?;
+        let pin = match initial_high{
+            true => pin.into_output_high(),
+            false => pin.into_output_low(),
+        };
+        return Ok(Box::new(RppalLine { address, pin }));
+    }
+    fn input(&self, address:u8, pull_up:bool) -> Result<Box<dyn InputLine>,String>{
+        let pin = self.gpio.get(address).map_err(|error| error.to_string())?;
+        let pin = match pull_up{
+            true => pin.into_input_pullup(),
+            false => pin.into_input(),
+        };
+        return Ok(Box::new(RppalInput { address, pin }));
+    }
+}
+
+#[cfg(feature = "hardware-gpio")]
+#[derive(Debug)]
+struct RppalLine{
+    address: u8,
+    pin: OutputPin,
+}
+
+#[cfg(feature = "hardware-gpio")]
+impl OutputLine for RppalLine{
+    fn set_high(&mut self){
+        self.pin.set_high();
+    }
+    fn set_low(&mut self){
+        self.pin.set_low();
+    }
+    fn is_set_high(&self) -> bool{
+        return self.pin.is_set_high();
+    }
+    fn get_address(&self) -> u8{
+        return self.address;
+    }
+}
+
+#[cfg(feature = "hardware-gpio")]
+#[derive(Debug)]
+struct RppalInput{
+    address: u8,
+    pin: InputPin,
+}
+
+#[cfg(feature = "hardware-gpio")]
+impl InputLine for RppalInput{
+    fn is_high(&self) -> bool{
+        return self.pin.is_high();
+    }
+    fn get_address(&self) -> u8{
+        return self.address;
+    }
+}
+
+//Linux GPIO character device (/dev/gpiochipN). Addresses are line offsets on
+//the chip, which match BCM numbers on a Pi and can be exercised with gpio-sim.
+#[derive(Debug)]
+pub struct CdevBackend{
+    chip: Mutex<Chip>,
+}
+
+impl CdevBackend{
+    pub fn new(chip_path:&str) -> Result<Self,String>{
+        match Chip::new(chip_path){
+            Ok(chip) => return Ok(CdevBackend { chip: Mutex::new(chip) }),
+            Err(error) => {
+                log::warn!("Unable to open GPIO chip {}!",chip_path);
+                log::debug!("{}",error);
+                return Err("Failed GPIO init".to_string());
+            }
+        }
+    }
+}
+
+impl GpioBackend for CdevBackend{
+    fn output(&self, address:u8, initial_high:bool) -> Result<Box<dyn OutputLine>,String>{
+        let mut chip = self.chip.lock().map_err(|_| "GPIO chip lock poisoned".to_string())?;
+        let line = chip.get_line(address as u32).map_err(|error| error.to_string())?;
+        let handle = line.request(LineRequestFlags::OUTPUT, initial_high as u8, CDEV_CONSUMER).map_err(|error| error.to_string())?;
+        return Ok(Box::new(CdevLine { address, handle }));
+    }
+    fn input(&self, address:u8, _pull_up:bool) -> Result<Box<dyn InputLine>,String>{
+        let mut chip = self.chip.lock().map_err(|_| "GPIO chip lock poisoned".to_string())?;
+        let line = chip.get_line(address as u32).map_err(|error| error.to_string())?;
+        let handle = line.request(LineRequestFlags::INPUT, 0, CDEV_CONSUMER).map_err(|error| error.to_string())?;
+        return Ok(Box::new(CdevInput { address, handle }));
+    }
+}
+
+#[derive(Debug)]
+struct CdevInput{
+    address: u8,
+    handle: LineHandle,
+}
+
+impl InputLine for CdevInput{
+    fn is_high(&self) -> bool{
+        return self.handle.get_value().unwrap_or(0) == 1;
+    }
+    fn get_address(&self) -> u8{
+        return self.address;
+    }
+}
+
+#[derive(Debug)]
+struct CdevLine{
+    address: u8,
+    handle: LineHandle,
+}
+
+impl OutputLine for CdevLine{
+    fn set_high(&mut self){
+        if let Err(error) = self.handle.set_value(1){
+            log::warn!("Unable to set GPIO line {} high: {}",self.address,error);
+        }
+    }
+    fn set_low(&mut self){
+        if let Err(error) = self.handle.set_value(0){
+            log::warn!("Unable to set GPIO line {} low: {}",self.address,error);
+        }
+    }
+    fn is_set_high(&self) -> bool{
+        return self.handle.get_value().unwrap_or(0) == 1;
+    }
+    fn get_address(&self) -> u8{
+        return self.address;
+    }
+}
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct LevelChange{
+    pub address: u8,
+    pub high: bool,
+    pub time: Instant,
+}
+
+//In-memory backend for running without hardware. Every level change on every
+//line is appended to a shared log, which clones of the backend also see.
+#[derive(Clone,Debug,Default)]
+pub struct MockBackend{
+    changes: Arc<Mutex<Vec<LevelChange>>>,
+    inputs: Arc<Mutex<HashMap<u8,bool>>>,
+    unavailable: HashSet<u8>,
+}
+
+impl MockBackend{
+    pub fn new() -> Self{
+        return MockBackend::default();
+    }
+
+    //Lines that should fail to be requested, as if claimed elsewhere.
+    pub fn with_unavailable(mut self, addresses:&[u8]) -> Self{
+        self.unavailable.extend(addresses.iter().copied());
+        return self;
+    }
+
+    pub fn get_changes(&self) -> Vec<LevelChange>{
+        return self.changes.lock().map(|changes| changes.clone()).unwrap_or_default();
+    }
+
+    //Drives a simulated input; inputs read low until set.
+    pub fn set_input(&self, address:u8, high:bool){
+        if let Ok(mut inputs) = self.inputs.lock(){
+            inputs.insert(address,high);
+        }
+    }
+
+    pub fn is_high(&self, address:u8) -> bool{
+        return self.get_changes().iter().rev()
+            .find(|change| change.address == address)
+            .is_some_and(|change| change.high);
+    }
+}
+
+impl GpioBackend for MockBackend{
+    fn output(&self, address:u8, initial_high:bool) -> Result<Box<dyn OutputLine>,String>{
+        if self.unavailable.contains(&address){
+            return Err(format!("Mock GPIO line {} is unavailable",address));
+        }
+        let mut line = MockLine { address, high: !initial_high, changes: self.changes.clone() };
+        line.record(initial_high);
+        return Ok(Box::new(line));
+    }
+    fn input(&self, address:u8, _pull_up:bool) -> Result<Box<dyn InputLine>,String>{
+        if self.unavailable.contains(&address){
+            return Err(format!("Mock GPIO line {} is unavailable",address));
+        }
+        return Ok(Box::new(MockInput { address, inputs: self.inputs.clone() }));
+    }
+}
+
+#[derive(Debug)]
+struct MockInput{
+    address: u8,
+    inputs: Arc<Mutex<HashMap<u8,bool>>>,
+}
+
+impl InputLine for MockInput{
+    fn is_high(&self) -> bool{
+        return self.inputs.lock().map(|inputs| inputs.get(&self.address).copied().unwrap_or(false)).unwrap_or(false);
+    }
+    fn get_address(&self) -> u8{
+        return self.address;
+    }
+}
+
+#[derive(Debug)]
+struct MockLine{
+    address: u8,
+    high: bool,
+    changes: Arc<Mutex<Vec<LevelChange>>>,
+}
+
+impl MockLine{
+    fn record(&mut self, high:bool){
+        self.high = high;
+        if let Ok(mut changes) = self.changes.lock(){
+            changes.push(LevelChange { address: self.address, high, time: Instant::now() });
+        }
+    }
+}
+
+impl OutputLine for MockLine{
+    fn set_high(&mut self){
+        self.record(true);
+    }
+    fn set_low(&mut self){
+        self.record(false);
+    }
+    fn is_set_high(&self) -> bool{
+        return self.high;
+    }
+    fn get_address(&self) -> u8{
+        return self.address;
+    }
+}
+
+//Which lines drive relays, which way round they are wired, and how long a
+//relay contact takes to settle after switching.
+#[derive(Clone,Debug,PartialEq)]
+pub struct RelaySettings{
+    pub addresses: Vec<u8>,
+    pub active_low: HashSet<u8>,
+    pub on_settle: Duration,
+    pub off_settle: Duration,
+}
+
+impl Default for RelaySettings{
+    fn default() -> Self {
+        RelaySettings {
+            addresses: RELAY_ADDRESSES.to_vec(),
+            active_low: HashSet::new(),
+            on_settle: Duration::ZERO,
+            off_settle: Duration::ZERO,
+        }
+    }
+}
+
+impl RelaySettings{
+    pub fn is_active_low(&self, address:u8) -> bool{
+        return self.active_low.contains(&address);
+    }
+
+    //Requests the line already at its released level, so an active-low relay
+    //never clicks on while being claimed.
+    pub fn open(&self, backend:&dyn GpioBackend, address:u8) -> Result<Relay,String>{
+        let active_low = self.is_active_low(address);
+        let line = backend.output(address, active_low)?;
+        return Ok(Relay {
+            line,
+            active_low,
+            on_settle: self.on_settle,
+            off_settle: self.off_settle,
+        });
+    }
+}
+
+//An output line with relay polarity and settle times applied.
+#[derive(Debug)]
+pub struct Relay{
+    line: Box<dyn OutputLine>,
+    active_low: bool,
+    on_settle: Duration,
+    off_settle: Duration,
+}
+
+impl Relay{
+    pub fn energise(&mut self){
+        match self.active_low{
+            true => self.line.set_low(),
+            false => self.line.set_high(),
+        }
+        thread::sleep(self.on_settle);
+    }
+
+    pub fn release(&mut self){
+        match self.active_low{
+            true => self.line.set_high(),
+            false => self.line.set_low(),
+        }
+        thread::sleep(self.off_settle);
+    }
+
+    pub fn is_energised(&self) -> bool{
+        return self.line.is_set_high() != self.active_low;
+    }
+
+    pub fn get_address(&self) -> u8{
+        return self.line.get_address();
+    }
+}
+
+//Picks a backend by name: "rppal", "cdev" or "mock".
+pub fn open_backend(name:&str, chip_path:&str) -> Result<Arc<dyn GpioBackend>,String>{
+    match name{
+        #[cfg(feature = "hardware-gpio")]
+        "rppal" => return Ok(Arc::new(RppalBackend::new()?)),
+        #[cfg(not(feature = "hardware-gpio"))]
+        "rppal" => return Err("Built without the hardware-gpio feature; use the cdev or mock backend".to_string()),
+        "cdev" => return Ok(Arc::new(CdevBackend::new(chip_path)?)),
+        "mock" => return Ok(Arc::new(MockBackend::new())),
+        _ => return Err(format!("Unknown GPIO backend: {}",name)),
+    }
+}
+
+//Hands out relays one owner at a time. Every configured relay that can be
+//claimed is driven to its released level up front and put in the free list.
+#[derive(Debug)]
+pub struct RelayAllocator{
+    backend: Arc<dyn GpioBackend>,
+    settings: RelaySettings,
+    free_addresses: Mutex<Vec<u8>>,
+    leased: Mutex<Vec<Weak<Mutex<Relay>>>>,
+}
+
+impl RelayAllocator{
+    pub fn new(backend:Arc<dyn GpioBackend>, settings:RelaySettings) -> Arc<Self> {
+        let mut free_addresses = Vec::new();
+        for pin in settings.addresses.iter(){
+            match settings.open(backend.as_ref(),*pin){
+                Ok(_)=>{
+                    free_addresses.push(*pin);
                 },
                 Err(error) => {
-                    log::warn!("Unable to open GPIO!");
+                    log::warn!("Pin unavailable!");
                     log::debug!("{}",error);
                 }
             }
         }
-        return output;
+        return Arc::new(RelayAllocator { backend, settings, free_addresses: Mutex::new(free_addresses), leased: Mutex::new(Vec::new()) });
+    }
+
+    pub fn lease(self:&Arc<Self>, address:u8) -> Result<RelayLease,String>{
+        let mut free_addresses = self.free_addresses.lock().map_err(|_| "Relay allocator lock poisoned".to_string())?;
+        if !free_addresses.contains(&address){
+            return Err(format!("Relay {} is not available; already leased?",address));
+        }
+        let relay = Arc::new(Mutex::new(self.settings.open(self.backend.as_ref(),address)?));
+        free_addresses.retain(|x| *x != address);
+        if let Ok(mut leased) = self.leased.lock(){
+            leased.retain(|relay| relay.strong_count() > 0);
+            leased.push(Arc::downgrade(&relay));
+        }
+        return Ok(RelayLease { relay: Some(relay), allocator: self.clone() });
+    }
+
+    //Every address this allocator was configured with, free or not.
+    pub fn get_addresses(&self) -> &[u8]{
+        return &self.settings.addresses;
+    }
+
+    pub fn get_free_addresses(&self) -> Vec<u8>{
+        return self.free_addresses.lock().map(|addresses| addresses.clone()).unwrap_or_default();
+    }
+
+    pub fn is_free(&self, address:u8) -> bool{
+        return self.get_free_addresses().contains(&address);
+    }
+
+    //Releases every leased relay, even one whose holder is stuck, e.g. when stopping.
+    pub fn release_all(&self){
+        let leased:Vec<Arc<Mutex<Relay>>> = match self.leased.lock(){
+            Ok(leased) => leased.iter().filter_map(|relay| relay.upgrade()).collect(),
+            Err(_) => return,
+        };
+        for relay in leased.iter(){
+            lock_relay(relay).release();
+        }
     }
 
-    pub fn remove_address(&mut self, address:u8) -> &mut Self {
-        self.unassigned_addresses.retain(|x| *x != address);
-        self
+    //Takes a relay out of circulation for the rest of the run.
+    pub fn exclude(&self, address:u8){
+        if let Ok(mut free_addresses) = self.free_addresses.lock(){
+            free_addresses.retain(|x| *x != address);
+        }
     }
-    
-    pub fn get_unassigned_addresses(&self) -> &Vec<u8>{
-        return &self.unassigned_addresses;
+
+    //Claims each free relay line and drives it on and off in turn. Only the
+    //output latch is read back, so this finds lines that cannot be claimed or
+    //driven, not relays whose contacts are stuck.
+    pub fn check_lines(self:&Arc<Self>) -> Vec<RelayFault>{
+        let mut faults = Vec::new();
+        for address in self.get_free_addresses(){
+            match self.lease(address){
+                Ok(mut lease) => {
+                    lease.energise();
+                    let energised = lease.is_energised();
+                    lease.release();
+                    let released = !lease.is_energised();
+                    if !energised{
+                        faults.push(RelayFault { address, reason: "output did not latch energised".to_string() });
+                    }
+                    else if !released{
+                        faults.push(RelayFault { address, reason: "output did not latch released".to_string() });
+                    }
+                },
+                Err(error) => faults.push(RelayFault { address, reason: error }),
+            }
+        }
+        return faults;
+    }
+
+    fn give_back(&self, address:u8){
+        if let Ok(mut free_addresses) = self.free_addresses.lock(){
+            if !free_addresses.contains(&address){
+                free_addresses.push(address);
+            }
+        }
+    }
+}
+
+#[derive(Clone,Debug,PartialEq)]
+pub struct RelayFault{
+    pub address: u8,
+    pub reason: String,
+}
+
+//Exclusive use of one relay. Dropping the lease releases the relay and then
+//returns its address to the allocator.
+#[derive(Debug)]
+pub struct RelayLease{
+    //Shared only with the allocator, so release_all can reach it.
+    relay: Option<Arc<Mutex<Relay>>>,
+    allocator: Arc<RelayAllocator>,
+}
+
+fn lock_relay(relay:&Mutex<Relay>) -> MutexGuard<'_, Relay>{
+    return relay.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+}
+
+impl RelayLease{
+    pub fn energise(&mut self){
+        if let Some(ref relay) = self.relay{
+            lock_relay(relay).energise();
+        }
+    }
+
+    pub fn release(&mut self){
+        if let Some(ref relay) = self.relay{
+            lock_relay(relay).release();
+        }
+    }
+
+    pub fn is_energised(&self) -> bool{
+        return self.relay.as_ref().is_some_and(|relay| lock_relay(relay).is_energised());
+    }
+
+    pub fn get_address(&self) -> u8{
+        return self.relay.as_ref().map(|relay| lock_relay(relay).get_address()).unwrap_or_default();
+    }
+}
+
+impl Drop for RelayLease{
+    fn drop(&mut self){
+        if let Some(relay) = self.relay.take(){
+            let address = {
+                let mut relay = lock_relay(&relay);
+                relay.release();
+                relay.get_address()
+            };
+            //The line itself must be closed before anyone else can claim it.
+            drop(relay);
+            self.allocator.give_back(address);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn allocator(backend:&MockBackend, addresses:&[u8], active_low:&[u8]) -> Arc<RelayAllocator>{
+        let settings = RelaySettings {
+            addresses: addresses.to_vec(),
+            active_low: active_low.iter().copied().collect(),
+            ..Default::default()
+        };
+        return RelayAllocator::new(Arc::new(backend.clone()),settings);
+    }
+
+    #[test]
+    fn relays_start_released(){
+        let backend = MockBackend::new();
+        allocator(&backend,&[4,5],&[5]);
+        assert!(!backend.is_high(4));
+        assert!(backend.is_high(5));
+    }
+
+    #[test]
+    fn a_relay_is_leased_to_one_owner_at_a_time(){
+        let backend = MockBackend::new();
+        let relays = allocator(&backend,&[4,5],&[]);
+        let lease = relays.lease(4).unwrap();
+        assert!(!relays.is_free(4));
+        assert!(relays.lease(4).is_err());
+        assert!(relays.lease(6).is_err());
+        drop(lease);
+        assert!(relays.is_free(4));
+        assert!(relays.lease(4).is_ok());
+    }
+
+    #[test]
+    fn dropping_a_lease_releases_the_relay(){
+        let backend = MockBackend::new();
+        let relays = allocator(&backend,&[4],&[]);
+        let mut lease = relays.lease(4).unwrap();
+        lease.energise();
+        assert!(backend.is_high(4));
+        drop(lease);
+        assert!(!backend.is_high(4));
+    }
+
+    #[test]
+    fn active_low_relays_are_driven_low_to_energise(){
+        let backend = MockBackend::new();
+        let relays = allocator(&backend,&[4],&[4]);
+        let mut lease = relays.lease(4).unwrap();
+        lease.energise();
+        assert!(lease.is_energised());
+        assert!(!backend.is_high(4));
+        lease.release();
+        assert!(!lease.is_energised());
+        assert!(backend.is_high(4));
+        //Claiming the line must not glitch it low on the way.
+        assert!(backend.get_changes().iter().filter(|change| change.address == 4).take(2).all(|change| change.high));
+    }
+
+    #[test]
+    fn release_all_reaches_leased_relays(){
+        let backend = MockBackend::new();
+        let relays = allocator(&backend,&[4,5],&[]);
+        let mut first = relays.lease(4).unwrap();
+        let mut second = relays.lease(5).unwrap();
+        first.energise();
+        second.energise();
+        relays.release_all();
+        assert!(!first.is_energised());
+        assert!(!second.is_energised());
+    }
+
+    #[test]
+    fn unavailable_lines_are_left_out(){
+        let backend = MockBackend::new().with_unavailable(&[5]);
+        let relays = allocator(&backend,&[4,5],&[]);
+        assert_eq!(relays.get_free_addresses(),vec![4]);
+        assert_eq!(relays.get_addresses(),&[4,5]);
+    }
+
+    #[test]
+    fn line_check_drives_each_free_relay_and_gives_it_back(){
+        let backend = MockBackend::new();
+        let relays = allocator(&backend,&[4,5],&[5]);
+        let _held = relays.lease(5).unwrap();
+        assert!(relays.check_lines().is_empty());
+        let driven:Vec<bool> = backend.get_changes().iter().filter(|change| change.address == 4).map(|change| change.high).collect();
+        assert!(driven.contains(&true));
+        assert!(!backend.is_high(4));
+        assert!(relays.is_free(4));
+        assert!(!relays.is_free(5));
+    }
+
+    #[test]
+    fn inputs_follow_set_input(){
+        let backend = MockBackend::new();
+        let input = backend.input(21,true).unwrap();
+        assert!(!input.is_high());
+        backend.set_input(21,true);
+        assert!(input.is_high());
     }
 }
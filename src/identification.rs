@@ -0,0 +1,136 @@
+use std::{collections::HashSet, io::{stdin, stdout, Write}, sync::mpsc::{self, Sender, TryRecvError}, thread, time::Duration};
+use regex::Regex;
+use crate::device::Device;
+
+//Checks serials, whether read from units or entered by the operator, against
+//the expected format and against every serial already seen this session, so
+//scanner double-reads and swapped labels are caught before they are written
+//to a results file. The pattern must match the whole serial.
+#[derive(Debug)]
+pub struct SerialValidator{
+    pattern: Option<Regex>,
+    seen: HashSet<String>,
+}
+
+impl SerialValidator{
+    pub fn new(pattern:Option<&str>) -> Result<Self,String>{
+        let pattern = match pattern{
+            //Anchored, so a double scan such as ABC123ABC123 does not pass for ABC123.
+            Some(pattern) => Some(Regex::new(&format!("^(?:{})$",pattern)).map_err(|error| format!("Invalid serial pattern {}: {}",pattern,error))?),
+            None => None,
+        };
+        return Ok(SerialValidator { pattern, seen: HashSet::new() });
+    }
+
+    //Returns the cleaned-up serial and records it, or says why it was rejected.
+    pub fn accept(&mut self, input:&str) -> Result<String,String>{
+        let serial = input.trim().to_string();
+        if serial.is_empty(){
+            return Err("No serial entered.".to_string());
+        }
+        if let Some(ref pattern) = self.pattern{
+            if !pattern.is_match(&serial){
+                return Err(format!("Serial {} does not match the expected format {}.",serial,pattern.as_str()));
+            }
+        }
+        if self.seen.contains(&serial){
+            return Err(format!("Serial {} has already been entered this session.",serial));
+        }
+        self.seen.insert(serial.clone());
+        return Ok(serial);
+    }
+}
+
+fn spawn_reader(prompt:&str, sender:Sender<Option<String>>){
+    print!("{}",prompt);
+    _ = stdout().flush();
+    thread::spawn(move ||{
+        let mut user_input = String::new();
+        match stdin().read_line(&mut user_input){
+            Ok(0) | Err(_) => _ = sender.send(None),
+            Ok(_) => _ = sender.send(Some(user_input)),
+        }
+    });
+}
+
+//Toggles the device's brightness until the operator enters a serial that the
+//validator accepts, then leaves the screen dark. None if stdin is closed.
+pub fn blink_until_serial(device:&mut Device, validator:&mut SerialValidator, prompt:&str, blink_interval:Duration) -> Option<String>{
+    let (sender, receiver) = mpsc::channel();
+    spawn_reader(prompt, sender.clone());
+    let mut bright = false;
+    loop{
+        match receiver.try_recv(){
+            Ok(Some(input)) => {
+                log::debug!("{}:{}",prompt,input.trim());
+                match validator.accept(&input){
+                    Ok(serial) => {
+                        device.darken_screen();
+                        return Some(serial);
+                    },
+                    Err(error) => {
+                        log::warn!("{} Please re-enter.",error);
+                        spawn_reader(prompt, sender.clone());
+                    }
+                }
+            },
+            Ok(None) | Err(TryRecvError::Disconnected) => {
+                log::error!("Input closed while waiting for a serial.");
+                device.darken_screen();
+                return None;
+            },
+            Err(TryRecvError::Empty) => {},
+        }
+        if bright{
+            device.darken_screen();
+        }
+        else{
+            device.brighten_screen();
+        }
+        bright = !bright;
+        thread::sleep(blink_interval);
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn the_pattern_must_match_the_whole_serial(){
+        let mut validator = SerialValidator::new(Some("[A-Z]{3}[0-9]{3}")).unwrap();
+        assert_eq!(validator.accept("  ABC123\n"),Ok("ABC123".to_string()));
+        assert!(validator.accept("ABC123ABC123").unwrap_err().contains("does not match"));
+        assert!(validator.accept("XABC124").is_err());
+        assert!(validator.accept("ABC1245").is_err());
+    }
+
+    #[test]
+    fn alternatives_in_the_pattern_are_anchored_together(){
+        let mut validator = SerialValidator::new(Some("RES[0-9]+|SEY[0-9]+")).unwrap();
+        assert!(validator.accept("RES1").is_ok());
+        assert!(validator.accept("SEY2").is_ok());
+        assert!(validator.accept("RES3-extra").is_err());
+        assert!(validator.accept("old-SEY4").is_err());
+    }
+
+    #[test]
+    fn a_serial_seen_in_one_slot_is_refused_in_another(){
+        let mut validator = SerialValidator::new(None).unwrap();
+        assert!(validator.accept("RES1").is_ok());
+        assert!(validator.accept("RES2").is_ok());
+        assert!(validator.accept(" RES1 ").unwrap_err().contains("already been entered"));
+    }
+
+    #[test]
+    fn without_a_pattern_anything_but_a_blank_is_accepted(){
+        let mut validator = SerialValidator::new(None).unwrap();
+        assert_eq!(validator.accept("any old label 7"),Ok("any old label 7".to_string()));
+        assert_eq!(validator.accept("  \n"),Err("No serial entered.".to_string()));
+    }
+
+    #[test]
+    fn an_invalid_pattern_is_refused(){
+        assert!(SerialValidator::new(Some("RES[0-9")).unwrap_err().starts_with("Invalid serial pattern"));
+    }
+}
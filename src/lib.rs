@@ -1,3 +1,22 @@
 pub mod gpio_facade;
 pub mod tty;
 pub mod device;
+pub mod config;
+pub mod network;
+pub mod lock;
+pub mod identification;
+pub mod fixture;
+pub mod pause;
+pub mod status;
+pub mod cli;
+pub mod plan;
+pub mod script;
+pub mod step;
+pub mod target;
+pub mod session;
+pub mod schedule;
+pub mod shutdown;
+pub mod retry;
+pub mod coordinator;
+#[cfg(test)]
+mod test_unit;
@@ -0,0 +1,184 @@
+use std::{fs::{self, OpenOptions}, io::{ErrorKind, Write}, path::{Path, PathBuf}, process};
+
+pub const DEFAULT_LOCK_DIRECTORY: &str = "/var/lock";
+const LOCK_PREFIX: &str = "LCK..";
+const RELAY_LOCK_NAME: &str = "seymour-relays";
+//Each stale lock cleared costs one attempt.
+const ACQUIRE_ATTEMPTS: usize = 3;
+
+//UUCP-style lock file: /var/lock/LCK..<name> holding the owner's PID as ten
+//right-aligned ASCII digits. Removed again when dropped. A lock directory that
+//cannot be written to is an error; locking can be turned off in [locking].
+#[derive(Debug)]
+pub struct LockFile{
+    path: PathBuf,
+}
+
+impl LockFile{
+    pub fn acquire(path:&Path) -> Result<Self,String>{
+        for _ in 0..ACQUIRE_ATTEMPTS{
+            match OpenOptions::new().write(true).create_new(true).open(path){
+                Ok(mut file) => {
+                    if let Err(error) = file.write_all(format!("{:>10}\n",process::id()).as_bytes()){
+                        _ = fs::remove_file(path);
+                        return Err(format!("Unable to write lock file {}: {}",path.display(),error));
+                    }
+                    log::debug!("Acquired lock {}",path.display());
+                    return Ok(LockFile { path: path.to_path_buf() });
+                },
+                Err(error) if error.kind() == ErrorKind::AlreadyExists => {
+                    match lock_owner(path){
+                        Some(pid) if process_alive(pid) => {
+                            return Err(format!("{} is locked by process {} ({})",path.display(),pid,process_name(pid)));
+                        },
+                        _ => remove_stale(path),
+                    }
+                },
+                Err(error) if error.kind() == ErrorKind::PermissionDenied || error.kind() == ErrorKind::NotFound => {
+                    return Err(format!("Unable to create lock file {}: {}; set enabled = false under [locking] to run without locks",path.display(),error));
+                },
+                Err(error) => {
+                    return Err(format!("Unable to create lock file {}: {}",path.display(),error));
+                }
+            }
+        }
+        return Err(format!("Unable to acquire lock file {}",path.display()));
+    }
+
+    pub fn get_path(&self) -> &Path{
+        return &self.path;
+    }
+}
+
+impl Drop for LockFile{
+    fn drop(&mut self){
+        if lock_owner(&self.path) == Some(process::id()){
+            _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+//Moves the lock aside before deleting it, so that when two instances clear the
+//same stale lock, neither can delete the fresh lock the other has just taken.
+fn remove_stale(path:&Path){
+    let aside = PathBuf::from(format!("{}.stale.{}",path.display(),process::id()));
+    if fs::rename(path,&aside).is_err(){
+        //Already cleared by someone else.
+        return;
+    }
+    match lock_owner(&aside){
+        Some(pid) if process_alive(pid) => {
+            //Taken afresh since it was found stale; put it back.
+            _ = fs::hard_link(&aside,path);
+        },
+        _ => log::warn!("Removed stale lock file {}",path.display()),
+    }
+    _ = fs::remove_file(&aside);
+}
+
+fn lock_owner(path:&Path) -> Option<u32>{
+    return fs::read_to_string(path).ok()?.trim().parse().ok();
+}
+
+fn process_alive(pid:u32) -> bool{
+    return Path::new(&format!("/proc/{}",pid)).exists();
+}
+
+fn process_name(pid:u32) -> String{
+    return fs::read_to_string(format!("/proc/{}/comm",pid))
+        .map(|name| name.trim().to_string())
+        .unwrap_or("unknown".to_string());
+}
+
+//Lock for a serial location. Local ports are locked by their real device name,
+//so /dev/serial/by-path links and minicom on /dev/ttyUSB0 agree on the same file.
+pub fn serial_lock_path(lock_directory:&str, serial_location:&str) -> PathBuf{
+    let device_name = match fs::canonicalize(serial_location){
+        Ok(real_path) => real_path.file_name().map(|name| name.to_string_lossy().to_string()),
+        Err(_) => None,
+    }.unwrap_or(serial_location.replace(|character:char| !character.is_ascii_alphanumeric() && character != '.', "_"));
+    return Path::new(lock_directory).join(format!("{}{}",LOCK_PREFIX,device_name));
+}
+
+pub fn relay_lock_path(lock_directory:&str) -> PathBuf{
+    return Path::new(lock_directory).join(LOCK_PREFIX.to_owned() + RELAY_LOCK_NAME);
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    //A fresh lock directory for one test.
+    fn lock_directory(name:&str) -> PathBuf{
+        let directory = PathBuf::from(format!("target/test-output/locks-{}",name));
+        _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        return directory;
+    }
+
+    fn write_owner(path:&Path, pid:u32){
+        fs::write(path,format!("{:>10}\n",pid)).unwrap();
+    }
+
+    #[test]
+    fn a_free_lock_is_taken_and_given_back(){
+        let path = relay_lock_path(lock_directory("free").to_str().unwrap());
+        let lock = LockFile::acquire(&path).unwrap();
+        assert_eq!(lock.get_path(),path.as_path());
+        assert_eq!(fs::read_to_string(&path).unwrap(),format!("{:>10}\n",process::id()));
+        drop(lock);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn a_lock_held_by_a_live_process_is_refused(){
+        let path = relay_lock_path(lock_directory("held").to_str().unwrap());
+        write_owner(&path,1);
+        let error = LockFile::acquire(&path).unwrap_err();
+        assert!(error.contains("is locked by process 1"),"{}",error);
+        assert_eq!(lock_owner(&path),Some(1));
+    }
+
+    #[test]
+    fn a_stale_lock_is_cleared_and_taken(){
+        let directory = lock_directory("stale");
+        let path = relay_lock_path(directory.to_str().unwrap());
+        for stale in ["4194303999\n", "not a pid\n"]{
+            fs::write(&path,stale).unwrap();
+            let lock = LockFile::acquire(&path).unwrap();
+            assert_eq!(lock_owner(&path),Some(process::id()));
+            drop(lock);
+        }
+        assert_eq!(fs::read_dir(&directory).unwrap().count(),0);
+    }
+
+    #[test]
+    fn dropping_a_lock_leaves_one_another_process_has_taken(){
+        let path = relay_lock_path(lock_directory("taken-over").to_str().unwrap());
+        let lock = LockFile::acquire(&path).unwrap();
+        write_owner(&path,1);
+        drop(lock);
+        assert_eq!(lock_owner(&path),Some(1));
+    }
+
+    #[test]
+    fn a_missing_lock_directory_says_how_to_turn_locking_off(){
+        let error = LockFile::acquire(Path::new("target/test-output/no-such-directory/LCK..x")).unwrap_err();
+        assert!(error.contains("[locking]"),"{}",error);
+    }
+
+    #[test]
+    fn ports_are_locked_by_their_real_device_name(){
+        let directory = lock_directory("names");
+        let device = directory.join("ttyUSB7");
+        fs::write(&device,"").unwrap();
+        let link = directory.join("platform-usb-0:1.2:1.0-port0");
+        symlink(fs::canonicalize(&device).unwrap(),&link).unwrap();
+        let expected = Path::new("/var/lock").join("LCK..ttyUSB7");
+        assert_eq!(serial_lock_path("/var/lock",link.to_str().unwrap()),expected);
+        assert_eq!(serial_lock_path("/var/lock",device.to_str().unwrap()),expected);
+        assert_eq!(serial_lock_path("/var/lock","127.0.0.1:47020"),Path::new("/var/lock/LCK..127.0.0.1_47020"));
+        assert_eq!(serial_lock_path("/var/lock","rfc2217://bench:4001"),Path::new("/var/lock/LCK..rfc2217___bench_4001"));
+    }
+}
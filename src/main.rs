@@ -1,142 +1,845 @@
-use seymour_poc_rust::{device::Device, tty::{self,TTY,Response},gpio_facade::GpioPins};
-use std::{io::{stdin,stdout,Write},thread::{self, JoinHandle},path::Path,fs};
+use seymour_poc_rust::{device::{self,Device}, tty::{self,TTY,Response},gpio_facade::{self,GpioBackend,RelayAllocator},config::Config,lock::{self,LockFile},
+    identification::{self,SerialValidator},fixture::FixtureMap,pause::{self,PauseSignal},status::{self,StatusBoard,RunState},plan::TestPlan,script::TestScript,session::{Session,SessionState},schedule::{Schedule,ScheduleState},shutdown::{self,ShutdownSignal},coordinator::Coordinator,
+    cli::{Cli,CliCommand,EnrolArgs,RunArgs}};
+use clap::Parser;
+use std::{io::{stdin,stdout,Write},thread::{self, JoinHandle},path::Path,fs,process,time::Duration,sync::{Arc,Mutex}};
 use chrono::{DateTime,Local};
 
 const VERSION:&str="2.0.1";
+const SERIAL_BY_PATH:&str="/dev/serial/by-path";
 
-fn int_input_filtering(prompt:Option<&str>) -> u64{
+//None once stdin is closed, Some(0) for anything that is not a number.
+fn int_input_filtering(prompt:Option<&str>) -> Option<u64>{
     let internal_prompt = prompt.unwrap_or(">>>");
     let mut user_input:String = String::new();
     print!("{}",internal_prompt);
     _ = stdout().flush();
-    stdin().read_line(&mut user_input).expect("Did not input a valid number.");
+    match stdin().read_line(&mut user_input){
+        Ok(0) | Err(_) => return None,
+        Ok(_) => {},
+    }
     if let Some('\n')=user_input.chars().next_back() {
         user_input.pop();
     }
     if let Some('\r')=user_input.chars().next_back() {
         user_input.pop();
     }
-    return user_input.parse().unwrap_or(0);
+    return Some(user_input.parse().unwrap_or(0));
 }
 
-fn input_filtering(prompt:Option<&str>) -> String{
-    let internal_prompt = prompt.unwrap_or(">>>");
-    let mut user_input:String = String::new();
-    print!("{}",internal_prompt);
-    _ = stdout().flush();
-    stdin().read_line(&mut user_input).ok().expect("Did not enter a correct string");
-    if let Some('\n')=user_input.chars().next_back() {
-        user_input.pop();
+//Local ports under /dev/serial/by-path, followed by any configured network consoles.
+fn find_ports(config:&Config) -> Result<Vec<String>,String>{
+    let mut ports:Vec<String> = Vec::new();
+    match fs::read_dir(SERIAL_BY_PATH){
+        Ok(available_ttys)=>{
+            for possible_tty in available_ttys{
+                match possible_tty{
+                    Ok(tty) => ports.push(tty.path().to_string_lossy().to_string()),
+                    Err(error) => log::debug!("{}",error),
+                }
+            }
+        },
+        Err(error)=>{
+            log::debug!("{}",error);
+            if config.network.ports.is_empty(){
+                return Err(format!("Invalid serial location! Please make sure that {} exists.",SERIAL_BY_PATH));
+            }
+        }
     }
-    if let Some('\r')=user_input.chars().next_back() {
-        user_input.pop();
+    ports.extend(config.network.ports.iter().cloned());
+    return Ok(ports);
+}
+
+//Devices on a known port that could not read their own serial take the one
+//recorded for that slot; ones that did read a serial are checked against it.
+fn apply_fixture_serials(devices:&mut [Device], fixture:&FixtureMap){
+    for device in devices.iter_mut(){
+        if let Some(slot) = fixture.slot_for_port(&device.get_port_name()){
+            if !device.has_serial(){
+                if !slot.serial.is_empty(){
+                    log::info!("Using serial {} for {} from the fixture file",slot.serial,slot.name);
+                    device.set_serial(&slot.serial);
+                }
+            }
+            else if device.get_serial() != slot.serial{
+                log::warn!("{} now holds device {}; the fixture file last saw {} there",slot.name,device.get_serial(),slot.serial);
+            }
+        }
     }
-    log::debug!("{}:{}",internal_prompt,user_input);
-    return user_input;
 }
 
-fn main(){
-    setup_logs();
-    log::info!("Seymour Life Testing version: {}",VERSION);
-    let gpio = &mut GpioPins::new();
-    match std::fs::read_dir("/dev/serial/by-path"){
-        Ok(available_ttys)=>{
-            let mut possible_devices:Vec<Option<Device>> = Vec::new();
-            let mut tty_test_threads:Vec<JoinHandle<Option<Device>>> = Vec::new();
-            for possible_tty in available_ttys.into_iter(){
-                tty_test_threads.push(
-                    thread::spawn(move ||{
-                        let tty_ref = possible_tty.as_ref();
-                        match tty_ref{
-                            Ok(tty_real_ref)=>{
-                                let tty_path =  tty_real_ref.path();
-                                let tty_name = tty_path.to_string_lossy();
-                                log::info!("Testing port {}. This may take a moment...",&tty_name);
-                                let possible_port = TTY::new(&tty_name);
-                                match possible_port{
-                                    Some(mut port) =>{
-                                        port.write_to_device(tty::Command::Newline);
-                                        let response = port.read_from_device(Some(":"));
-                                        if response != Response::Empty{
-                                            log::debug!("{} is valid port!",tty_name);
-                                            let new_device = Device::new(port,Some(response));
-                                            match new_device{
-                                                Ok(device) => Some(device),
-                                                Err(_) => None
-                                            }
-                                        }
-                                        else { None }
-                                    },
-                                    None=>{None}
-                                }
-                            },
-                            Err(error)=>{
-                                //log::warn!("Invalid TTY location");
-                                log::debug!("{}",error);
-                                None
-                            }
-                        }
-                }));
+//Spot-checks the relay recorded for this slot, and only probes every free relay if that fails.
+fn assign_relay(device:&mut Device, relays:&RelayAllocator, known_relay:Option<u8>){
+    if let Some(address) = known_relay{
+        if relays.is_free(address){
+            device.set_pin_address(address).start_temp();
+            let temp_running = device.is_temp_running();
+            device.stop_temp();
+            if temp_running{
+                return;
             }
-            for thread in tty_test_threads{
-                let output = thread.join().unwrap_or_else(|x|{log::trace!("{:?}",x); None});
-                possible_devices.push(output);
+        }
+        log::warn!("Relay {} from the fixture file no longer drives device {}; probing all relays.",address,device.get_serial());
+    }
+    let free_addresses = relays.get_free_addresses();
+    log::debug!("Number of unassigned addresses: {}",free_addresses.len());
+    for address in free_addresses{
+        device.set_pin_address(address).start_temp();
+        if device.is_temp_running(){
+            device.stop_temp();
+            return;
+        }
+        else{
+            device.stop_temp();
+        }
+    }
+    log::warn!("No relay found for device {}",device.get_serial());
+    device.clear_pin_address();
+}
+
+//Checks every relay line can be claimed and driven, leaving faulty ones out
+//of enrolment. This only sees the lines; check_temp_relays sees the contacts.
+fn check_relay_lines(relays:&Arc<RelayAllocator>){
+    log::info!("Running relay self-test...");
+    let tested_count = relays.get_free_addresses().len();
+    let faults = relays.check_lines();
+    for fault in faults.iter(){
+        log::warn!("Relay {} failed self-test: {}",fault.address,fault.reason);
+        relays.exclude(fault.address);
+    }
+    log::info!("Relay lines: {} passed, {} failed",tested_count.saturating_sub(faults.len()),faults.len());
+}
+
+//Checks each unit's temp reading follows the relay it was given. A unit that
+//fails runs without temp tests rather than on a relay that cannot be trusted.
+fn check_temp_relays(devices:&mut [Device]){
+    let mut passed_count = 0;
+    let mut failed_count = 0;
+    for device in devices.iter_mut().filter(|device| device.get_pin_address().is_some()){
+        match device.check_temp_relay(){
+            Ok(_) => passed_count += 1,
+            Err(reason) => {
+                log::warn!("Relay self-test failed for device {} ({}); running it without temp tests.",device.get_serial(),reason);
+                device.clear_pin_address();
+                failed_count += 1;
             }
+        }
+    }
+    log::info!("Relay self-test: {} passed, {} failed",passed_count,failed_count);
+}
 
-            let mut devices:Vec<Device> = Vec::new();
-            for possible_device in possible_devices.into_iter(){
-                if let Some(device) = possible_device{
-                    devices.push(device);
-                }
+//Watches the pause input, if one is configured. Devices share the returned signal.
+fn start_pause_watcher(gpio_backend:&Arc<dyn GpioBackend>, config:&Config) -> Option<PauseSignal>{
+    let settings = config.pause.get_settings()?;
+    match gpio_backend.input(settings.address,settings.pull_up){
+        Ok(line) => {
+            let signal = PauseSignal::new();
+            log::info!("Watching GPIO {} for pause requests.",settings.address);
+            pause::watch_input(line,settings,signal.clone());
+            return Some(signal);
+        },
+        Err(error) => {
+            log::warn!("Unable to watch pause input on GPIO {}; pausing is disabled.",settings.address);
+            log::debug!("{}",error);
+            return None;
+        }
+    }
+}
+
+//Power relays get their own allocator for their own polarity, so a line
+//shared with the temp relays would have two owners; that is refused.
+fn assign_power_relays(devices:&mut [Device], fixture:&FixtureMap, gpio_backend:&Arc<dyn GpioBackend>, relays:&RelayAllocator, config:&Config) -> Option<Arc<RelayAllocator>>{
+    let power_addresses = fixture.get_power_relays();
+    if power_addresses.is_empty(){
+        return None;
+    }
+    let shared:Vec<String> = power_addresses.iter().filter(|address| relays.get_addresses().contains(address)).map(|address| address.to_string()).collect();
+    if !shared.is_empty(){
+        log::error!("GPIO {} used as both temp and power relays; remove them from [relays] pins or the fixture file.",shared.join(", "));
+        process::exit(1);
+    }
+    let power_relays = RelayAllocator::new(gpio_backend.clone(),config.power.get_relay_settings(power_addresses));
+    for device in devices.iter_mut(){
+        let slot = match fixture.slot_for_port(&device.get_port_name()){
+            Some(slot) => slot,
+            None => continue,
+        };
+        if let Some(address) = slot.power_relay{
+            match power_relays.lease(address){
+                Ok(lease) => _ = device.set_power_relay(lease,config.power.get_settings()),
+                Err(error) => log::warn!("Power relay for {}: {}",slot.name,error),
             }
+        }
+    }
+    return Some(power_relays);
+}
 
-            log::info!("Number of devices detected: {}",devices.len());
+fn record_fixture(devices:&mut [Device], fixture:&mut FixtureMap){
+    for device in devices.iter_mut(){
+        let port = device.get_port_name();
+        let serial = device.get_serial().to_string();
+        if let Some(previous_slot) = fixture.slot_for_serial(&serial){
+            if previous_slot.port != port{
+                log::warn!("Device {} has moved from {} ({}) to {}",serial,previous_slot.name,previous_slot.port,port);
+            }
+        }
+        fixture.record(&port,device.get_pin_address(),&serial);
+    }
+}
 
-            log::info!("Dimming all screens...");
-            for device in devices.iter_mut(){
-                device.darken_screen();
+//Everything a run holds on to for its whole lifetime.
+struct Rig{
+    //Held so a second instance cannot drive our relays.
+    _relay_lock: Option<LockFile>,
+    gpio_backend: Arc<dyn GpioBackend>,
+    relays: Arc<RelayAllocator>,
+    //Set once enrolment has handed out power relays.
+    power_relays: Option<Arc<RelayAllocator>>,
+    //Watched from the moment the relays are claimed, so an e-stop also covers
+    //discovery and the relay self-test.
+    pause_signal: Option<PauseSignal>,
+    status_board: Arc<StatusBoard>,
+    //Handles stop signals from the moment the relays are claimed, so stopping
+    //during enrolment does not leave a relay energised.
+    shutdown: ShutdownSignal,
+    //The fixture map as saved, read even with ignore_previous so the fields
+    //entered by hand (power relays, status LEDs) survive a re-enrol.
+    fixture_file: Option<FixtureMap>,
+    //The serials and relays enrolment starts from.
+    previous_fixture: Option<FixtureMap>,
+}
+
+//The relay lock doubles as the lock on the whole rig, output files included.
+//Exits if it cannot be taken, e.g. because another instance holds it.
+fn lock_instance(config:&Config) -> Option<LockFile>{
+    match config.locking.get_directory(){
+        Some(lock_directory) => match LockFile::acquire(&lock::relay_lock_path(&lock_directory)){
+            Ok(relay_lock) => return Some(relay_lock),
+            Err(error) => {
+                log::error!("Unable to lock the relays: {}",error);
+                process::exit(1);
             }
+        },
+        None => return None,
+    }
+}
 
-            for device in devices.iter_mut(){
-                device.brighten_screen()
-                    .set_serial(&input_filtering(Some("Enter the serial of the device with the bright screen: ")).to_string())
-                .darken_screen();
-                log::debug!("Number of unassigned addresses: {}",gpio.get_unassigned_addresses().len());
-                for &address in gpio.get_unassigned_addresses(){
-                    device.set_pin_address(address).start_temp();
-                    if device.is_temp_running(){
-                        device.stop_temp();
-                        gpio.remove_address(address);
-                        break;
-                    }
-                    else{
-                        device.stop_temp();
+//With ignore_previous the old fixture map's serials and relays are not used,
+//so every unit is identified again; the new map is still saved over it.
+fn open_rig(config:&Config, ignore_previous:bool) -> Rig{
+    let relay_lock = lock_instance(config);
+    let gpio_backend = match gpio_facade::open_backend(&config.gpio.backend,&config.gpio.chip){
+        Ok(gpio_backend) => gpio_backend,
+        Err(error) => {
+            log::error!("{}",error);
+            process::exit(1);
+        }
+    };
+    let relay_settings = config.relays.get_settings();
+    let relays = RelayAllocator::new(gpio_backend.clone(),relay_settings);
+    let pause_signal = start_pause_watcher(&gpio_backend,config);
+    if let Some(ref pause_signal) = pause_signal{
+        pause_signal.release_on_pause(relays.clone());
+    }
+    let shutdown = ShutdownSignal::new();
+    shutdown.release_on_stop(relays.clone());
+    if let Err(error) = shutdown::install(shutdown.clone()){
+        log::warn!("Stop signals will end the run without waiting for devices.");
+        log::debug!("{}",error);
+    }
+    let fixture_file = match config.fixture.enabled{
+        true => FixtureMap::load(&config.fixture.path).unwrap_or_else(|error|{
+            log::warn!("{}",error);
+            None
+        }),
+        false => None
+    };
+    let previous_fixture = match ignore_previous{
+        true => None,
+        false => fixture_file.clone(),
+    };
+    let status_board = StatusBoard::new();
+    if let Some(ref pause_signal) = pause_signal{
+        status_board.set_pause_signal(pause_signal.clone());
+    }
+    let slot_leds = fixture_file.as_ref().map(|fixture| fixture.get_status_leds()).unwrap_or_default();
+    if status::drive_lights(gpio_backend.as_ref(),config.status.get_settings(),slot_leds,status_board.clone()).is_some(){
+        log::info!("Driving status lights.");
+    }
+    return Rig { _relay_lock: relay_lock, gpio_backend, relays, power_relays: None, pause_signal, status_board, shutdown, fixture_file, previous_fixture };
+}
+
+fn discover_devices(config:&Config, relays:&Arc<RelayAllocator>) -> Result<Vec<Device>,String>{
+    let available_ttys = find_ports(config)?;
+    let mut tty_test_threads:Vec<JoinHandle<Option<Device>>> = Vec::new();
+    for tty_name in available_ttys.into_iter(){
+        let serial_config = config.serial.clone();
+        let lock_directory = config.locking.get_directory();
+        let serial_command = config.identification.get_command();
+        let device_relays = relays.clone();
+        tty_test_threads.push(
+            thread::spawn(move ||{
+                log::info!("Testing port {}. This may take a moment...",&tty_name);
+                let mut settings = match serial_config.settings_for(&tty_name){
+                    Ok(settings) => settings,
+                    Err(error) => {
+                        log::warn!("Invalid serial settings for {}: {}",tty_name,error);
+                        return None;
                     }
+                };
+                settings.lock_directory = lock_directory;
+                let possible_port = TTY::new_with_settings(&tty_name,&settings);
+                match possible_port{
+                    Some(mut port) =>{
+                        port.write_to_device(tty::Command::Newline);
+                        let response = port.read_from_device(Some(":"));
+                        if response != Response::Empty{
+                            log::debug!("{} is valid port!",tty_name);
+                            let new_device = Device::new_with_relays(port,Some(response),device_relays);
+                            match new_device{
+                                Ok(mut device) => {
+                                    if let Some(command) = serial_command{
+                                        if let Some(serial) = device.read_serial(&command){
+                                            device.set_serial(&serial);
+                                        }
+                                    }
+                                    Some(device)
+                                },
+                                Err(_) => None
+                            }
+                        }
+                        else { None }
+                    },
+                    None=>{None}
                 }
+        }));
+    }
+    let mut devices:Vec<Device> = Vec::new();
+    for thread in tty_test_threads{
+        if let Some(device) = thread.join().unwrap_or_else(|x|{log::trace!("{:?}",x); None}){
+            devices.push(device);
+        }
+    }
+    log::info!("Number of devices detected: {}",devices.len());
+    return Ok(devices);
+}
+
+fn discover_or_exit(config:&Config, rig:&Rig) -> Vec<Device>{
+    match discover_devices(config,&rig.relays){
+        Ok(devices) => return devices,
+        Err(error) => {
+            log::error!("{}",error);
+            process::exit(1);
+        }
+    }
+}
+
+//Serials given on the command line, keyed by port or by fixture slot name.
+fn apply_serial_flags(devices:&mut [Device], serials:&[(String,String)], fixture:Option<&FixtureMap>){
+    for (slot_or_port, serial) in serials.iter(){
+        let port = fixture.and_then(|fixture| fixture.slots.iter().find(|slot| &slot.name == slot_or_port))
+            .map(|slot| slot.port.clone())
+            .unwrap_or(slot_or_port.clone());
+        match devices.iter_mut().find(|device| device.get_port_name() == port){
+            Some(device) => _ = device.set_serial(serial),
+            None => log::warn!("No device found on {} for serial {}",slot_or_port,serial),
+        }
+    }
+}
+
+//Gives every device a serial and a relay, then saves the fixture map. Exits
+//if a unit cannot be identified.
+fn enrol(config:&Config, rig:&mut Rig, devices:&mut [Device], enrol_args:&EnrolArgs){
+    let previous_fixture = rig.previous_fixture.as_ref();
+    if let Some(fixture) = previous_fixture{
+        apply_fixture_serials(devices,fixture);
+    }
+    apply_serial_flags(devices,&enrol_args.serials,previous_fixture);
+
+    let mut validator = match SerialValidator::new(config.identification.serial_pattern.as_deref()){
+        Ok(validator) => validator,
+        Err(error) => {
+            log::error!("{}",error);
+            process::exit(1);
+        }
+    };
+    //A serial that fails validation is dropped, so the unit is identified by hand.
+    for device in devices.iter_mut().filter(|device| device.has_serial()){
+        if let Err(error) = validator.accept(device.get_serial()){
+            log::warn!("Ignoring serial for the device on {}: {}",device.get_port_name(),error);
+            device.clear_serial();
+        }
+    }
+
+    let unidentified_count = devices.iter().filter(|device| !device.has_serial()).count();
+    if unidentified_count > 0 && enrol_args.no_prompt{
+        for device in devices.iter().filter(|device| !device.has_serial()){
+            log::error!("No serial given for the device on {}",device.get_port_name());
+        }
+        process::exit(1);
+    }
+    if unidentified_count > 0{
+        log::info!("{} device(s) need manual identification. Dimming all screens...",unidentified_count);
+        for device in devices.iter_mut(){
+            device.darken_screen();
+        }
+    }
+
+    let blink_interval = Duration::from_millis(config.identification.blink_interval_ms);
+    for device in devices.iter_mut().filter(|device| !device.has_serial()){
+        rig.status_board.set_run_state(RunState::WaitingForOperator);
+        rig.status_board.set_attention_port(Some(&device.get_port_name()));
+        match identification::blink_until_serial(device,&mut validator,"Enter the serial of the device with the blinking screen: ",blink_interval){
+            Some(serial) => _ = device.set_serial(&serial),
+            None => process::exit(1),
+        }
+    }
+    rig.status_board.set_attention_port(None);
+    rig.status_board.set_run_state(RunState::Starting);
+
+    if config.relays.self_test{
+        check_relay_lines(&rig.relays);
+    }
+
+    for device in devices.iter_mut(){
+        let known_relay = previous_fixture
+            .and_then(|fixture| fixture.slot_for_port(&device.get_port_name()))
+            .and_then(|slot| slot.relay);
+        assign_relay(device,&rig.relays,known_relay);
+    }
+
+    if config.relays.self_test{
+        check_temp_relays(devices);
+    }
+
+    //Power relays only ever come from the fixture file, so they are read from
+    //the map about to be saved, whichever units were identified again.
+    let mut fixture = rig.fixture_file.clone().unwrap_or_default();
+    record_fixture(devices,&mut fixture);
+    rig.power_relays = assign_power_relays(devices,&fixture,&rig.gpio_backend,&rig.relays,config);
+    if let Some(ref power_relays) = rig.power_relays{
+        if let Some(ref pause_signal) = rig.pause_signal{
+            pause_signal.release_on_pause(power_relays.clone());
+        }
+        rig.shutdown.release_on_stop(power_relays.clone());
+    }
+    let power_configured = !fixture.get_power_relays().is_empty() || config.power.hard_reboot_every > 0 || config.power.unresponsive_timeout_s > 0;
+    if power_configured && !devices.iter().any(|device| device.has_power_relay()){
+        log::warn!("Power cycling is configured but no unit has a power relay; set power_relay on the slots in {}{}.",
+            config.fixture.path,if config.fixture.enabled { "" } else { " and enable [fixture]" });
+    }
+
+    if config.fixture.enabled{
+        match fixture.save(&config.fixture.path){
+            Ok(_) => log::info!("Saved fixture map to {}",config.fixture.path),
+            Err(error) => log::warn!("{}",error),
+        }
+    }
+}
+
+//How long devices keep going. Each also stops early once it meets its targets.
+#[derive(Clone,Debug)]
+struct RunLength{
+    iterations: Option<u64>,
+    schedule: Schedule,
+}
+
+//Runs cycles until the iteration count is reached, the schedule runs out or
+//the device meets its targets, whichever comes first. A device that meets
+//them is retired. Outside the schedule's windows it waits between cycles.
+//On shutdown it is left safe wherever it stopped.
+fn run_device(device:Device, plan:&TestPlan, script:Option<&TestScript>, length:&RunLength, shutdown:&ShutdownSignal){
+    let iteration_count = length.iterations;
+    let schedule = &length.schedule;
+    let mut iteration = device.get_cycles();
+    device.stagger();
+    let shared = Arc::new(Mutex::new(device));
+    let device_script = script.map(|script| script.bind(&shared));
+    loop{
+        let mut device = shared.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if device.is_stopping(){
+            device.make_safe();
+            return;
+        }
+        if device.is_quarantine_due() && !device.attempt_recovery(){
+            device.quarantine();
+            return;
+        }
+        if device.is_complete(){
+            device.retire();
+            return;
+        }
+        if iteration_count.is_some_and(|iteration_count| iteration >= iteration_count){
+            return;
+        }
+        let serial = device.get_serial().to_string();
+        match schedule.get_state(){
+            ScheduleState::Over => {
+                log::info!("Run time is up for device {}.",serial);
+                return;
+            },
+            ScheduleState::Closed => {
+                log::info!("Device {} is outside the run windows; waiting for the next one.",serial);
+                device.stop_temp();
+                schedule.wait_for_window(shutdown);
+                continue;
+            },
+            ScheduleState::Open => {},
+        }
+        iteration += 1;
+        let remaining:Vec<String> = device.get_remaining().iter().map(|(label, value)| format!("{} {}",value,label)).collect();
+        match iteration_count{
+            Some(iteration_count) => log::info!("Starting iteration {} of {} for device {}...",iteration,iteration_count,serial),
+            None if remaining.is_empty() => log::info!("Starting iteration {} for device {}...",iteration,serial),
+            None => log::info!("Starting iteration {} for device {} ({} to go)...",iteration,serial,remaining.join(", ")),
+        }
+        match device_script{
+            Some(ref device_script) => {
+                drop(device);
+                device_script.run_cycle();
+            },
+            None => device.run_plan(plan),
+        }
+    }
+}
+
+//Once a stop is requested, wakes any paused devices and gives them the grace
+//period to reach a safe point before releasing every relay and exiting.
+fn watch_shutdown(shutdown:&ShutdownSignal, pause_signal:Option<PauseSignal>, grace:Duration){
+    let shutdown = shutdown.clone();
+    thread::spawn(move ||{
+        shutdown.wait_for_request();
+        if let Some(pause_signal) = pause_signal{
+            pause_signal.resume();
+        }
+        thread::sleep(grace);
+        log::error!("Devices did not reach a safe point within {}s; stopping anyway.",grace.as_secs());
+        shutdown.release_relays();
+        log::logger().flush();
+        process::exit(shutdown::EXIT_UNCLEAN);
+    });
+}
+
+fn run_iterations(config:&Config, rig:&Rig, mut devices:Vec<Device>, plan:&TestPlan, script:Option<&TestScript>, length:&RunLength, session:Arc<Session>){
+    let pause_signal = rig.pause_signal.clone();
+    if let Some(ref pause_signal) = pause_signal{
+        for device in devices.iter_mut(){
+            device.set_pause_signal(pause_signal.clone());
+        }
+    }
+    let shutdown = rig.shutdown.clone();
+    watch_shutdown(&shutdown,pause_signal,Duration::from_secs(config.shutdown.grace_s));
+    let coordinator = Arc::new(Coordinator::new(&config.limits.operations,config.limits.get_stagger(),config.limits.get_boot_budget()));
+    for (operation, limit) in config.limits.operations.iter(){
+        if *limit > 0{
+            log::info!("At most {} devices run {} at once",limit,operation);
+        }
+    }
+    for device in devices.iter_mut(){
+        device.set_shutdown_signal(shutdown.clone());
+        device.set_status_board(rig.status_board.clone());
+        device.set_session(session.clone());
+        let targets = config.targets.get_targets(plan,device.get_serial());
+        device.set_targets(targets);
+        //A script may record counts any way it likes, so only plans are checked.
+        if script.is_none(){
+            for (label, reason) in device.get_unreachable_targets(plan){
+                log::warn!("Device {} can never meet its {} target: {}.",device.get_serial(),label,reason);
             }
+        }
+        let policy = config.quarantine.get_policy(device.get_serial());
+        device.set_failure_policy(policy);
+        device.set_command_retries(config.retry.commands.clone());
+        device.set_coordinator(coordinator.clone());
+    }
+    rig.status_board.set_run_state(RunState::Running);
 
-            let mut iteration_count:u64 = 0;
-            while iteration_count < 1{
-                iteration_count = int_input_filtering(Some("Enter the number of iterations to complete: "));
+    if let Some(deadline) = length.schedule.deadline{
+        log::info!("Running until {}",deadline.to_rfc3339());
+    }
+    if !length.schedule.windows.is_empty(){
+        let windows:Vec<String> = length.schedule.windows.iter().map(|window| window.to_string()).collect();
+        log::info!("Running only between {}",windows.join(", "));
+    }
+
+    shutdown.stop_at_safe_points();
+    let mut iteration_threads = Vec::new();
+    while let Some(device) = devices.pop(){
+        let plan = plan.clone();
+        let script = script.cloned();
+        let length = length.clone();
+        let shutdown = shutdown.clone();
+        iteration_threads.push(thread::spawn(move||{
+            run_device(device,&plan,script.as_ref(),&length,&shutdown);
+        }));
+    }
+    for thread in iteration_threads{
+        thread.join().unwrap();
+    }
+    if shutdown.is_requested(){
+        shutdown.release_relays();
+        log::info!("Every device stopped at a safe point; use resume to carry on.");
+        log::logger().flush();
+        process::exit(shutdown::EXIT_STOPPED);
+    }
+    session.finish();
+}
+
+//Checkpoints the run from the start, so resume can pick it up after a power cut.
+fn start_session(config:&Config, rig:&Rig, devices:&mut [Device], length:&RunLength) -> Arc<Session>{
+    let mut fixture = rig.fixture_file.clone().unwrap_or_default();
+    record_fixture(devices,&mut fixture);
+    let state = SessionState {
+        started: Local::now().to_rfc3339(),
+        plan: config.plan.path.clone(),
+        script: config.plan.script.clone(),
+        iterations: length.iterations,
+        deadline: length.schedule.deadline.map(|deadline| deadline.to_rfc3339()),
+        windows: length.schedule.windows.clone(),
+        fixture,
+        devices: devices.iter().map(|device| device.get_progress()).collect(),
+    };
+    match Session::start(&config.session.path,state){
+        Ok(session) => return session,
+        Err(error) => {
+            log::error!("{}",error);
+            process::exit(1);
+        }
+    }
+}
+
+//Lists what is connected without sending anything to the units: each port
+//with its fixture slot and recorded serial and whether the unit is printing
+//anything, then the fixture slots whose port is missing.
+fn scan(config:&Config){
+    let ports = match find_ports(config){
+        Ok(ports) => ports,
+        Err(error) => {
+            log::error!("{}",error);
+            process::exit(1);
+        }
+    };
+    let fixture = match config.fixture.enabled{
+        true => FixtureMap::load(&config.fixture.path).unwrap_or_else(|error|{
+            log::warn!("{}",error);
+            None
+        }),
+        false => None
+    };
+    for port in ports.iter(){
+        let slot = fixture.as_ref().and_then(|fixture| fixture.slot_for_port(port));
+        let slot_name = slot.map(|slot| slot.name.clone()).unwrap_or("-".to_string());
+        let serial = slot.map(|slot| slot.serial.clone()).unwrap_or("-".to_string());
+        println!("{}\t{}\t{}\t{}",slot_name,serial,port,listen_to_port(config,port));
+    }
+    if let Some(ref fixture) = fixture{
+        for slot in fixture.slots.iter().filter(|slot| !ports.contains(&slot.port)){
+            println!("{}\t{}\t{}\tmissing",slot.name,slot.serial,slot.port);
+        }
+    }
+}
+
+//Opens the port and reads whatever arrives, without writing to it.
+fn listen_to_port(config:&Config, port:&str) -> &'static str{
+    let mut settings = match config.serial.settings_for(port){
+        Ok(settings) => settings,
+        Err(error) => {
+            log::warn!("Invalid serial settings for {}: {}",port,error);
+            return "invalid settings";
+        }
+    };
+    settings.lock_directory = config.locking.get_directory();
+    //Detecting the baud rate means writing to the unit.
+    settings.auto_baud = false;
+    match TTY::new_with_settings(port,&settings).map(|mut tty| tty.read_raw()){
+        Some(output) if output.is_empty() => return "quiet",
+        Some(_) => return "active",
+        None => return "unavailable",
+    }
+}
+
+fn report(){
+    let serials = device::recorded_serials();
+    if serials.is_empty(){
+        println!("No results recorded.");
+        return;
+    }
+    for serial in serials.iter(){
+        match device::read_counts(serial){
+            Ok(counts) => {
+                let totals:Vec<String> = counts.iter().map(|(label, value)| format!("{}: {}",label,value)).collect();
+                println!("{}\t{}",serial,totals.join("\t"));
+            },
+            Err(error) => log::warn!("{}",error),
+        }
+    }
+}
+
+fn load_plan(config:&Config) -> (TestPlan, Option<TestScript>){
+    let plan = match config.plan.get_plan(){
+        Ok(plan) => plan,
+        Err(error) => {
+            log::error!("{}",error);
+            process::exit(1);
+        }
+    };
+    let script = match config.plan.get_script(){
+        Ok(script) => script,
+        Err(error) => {
+            log::error!("{}",error);
+            process::exit(1);
+        }
+    };
+    match (&script, &plan.name){
+        (Some(_), _) => log::info!("Running test script {}",config.plan.script.as_deref().unwrap_or_default()),
+        (None, Some(name)) => log::info!("Using test plan {}",name),
+        (None, None) => {},
+    }
+    return (plan, script);
+}
+
+fn run(config:&Config, run_args:&RunArgs){
+    //Starting a run replaces the checkpoint that resume needs.
+    if Path::new(&config.session.path).is_file(){
+        match run_args.discard_session{
+            true => log::warn!("Discarding the interrupted session in {}",config.session.path),
+            false => {
+                log::error!("An interrupted session is waiting in {}; use resume to carry on, or run with --discard-session to start afresh.",config.session.path);
+                process::exit(1);
             }
+        }
+    }
+    let (plan, script) = load_plan(config);
+    let mut rig = open_rig(config,run_args.enrol.re_enrol);
+    let mut devices = discover_or_exit(config,&rig);
+    enrol(config,&mut rig,&mut devices,&run_args.enrol);
+    //A unit without targets would run forever with no iteration count to stop it.
+    let has_targets = devices.iter_mut().all(|device| !config.targets.get_targets(&plan,device.get_serial()).is_empty());
+    let has_duration = config.schedule.duration.is_some();
+    let iteration_count = match run_args.iterations{
+        Some(iteration_count) => Some(iteration_count),
+        None if has_targets || has_duration => None,
+        None if run_args.enrol.no_prompt => {
+            log::error!("--iterations is required with --no-prompt unless every unit has targets or a duration is set");
+            process::exit(1);
+        },
+        None => {
+            rig.status_board.set_run_state(RunState::WaitingForOperator);
+            loop{
+                match int_input_filtering(Some("Enter the number of iterations to complete: ")){
+                    Some(iteration_count) if iteration_count > 0 => break Some(iteration_count),
+                    Some(_) => println!("Please enter a whole number greater than zero."),
+                    None => process::exit(1),
+                }
+            }
+        }
+    };
+    let length = RunLength {
+        iterations: iteration_count,
+        schedule: Schedule::starting_now(config.schedule.duration,config.schedule.windows.clone()),
+    };
+    let session = start_session(config,&rig,&mut devices,&length);
+    run_iterations(config,&rig,devices,&plan,script.as_ref(),&length,session);
+}
 
-            let mut iteration_threads = Vec::new();
-            while let Some(mut device) = devices.pop(){
-                iteration_threads.push(thread::spawn(move||{
-                    for i in 1..=iteration_count{
-                        log::info!("Starting iteration {} of {} for device {}...",
-                                       i,iteration_count,device.get_serial());
-                        device.test_cycle(None, None);
-                    }
-                }));
+//Rebuilds an interrupted run from its session file: the same units in the same
+//slots, the same plan, and whatever iterations each unit had left.
+fn resume(config:&Config){
+    let state = match Session::load(&config.session.path){
+        Ok(state) => state,
+        Err(error) => {
+            log::error!("{}",error);
+            process::exit(1);
+        }
+    };
+    log::info!("Resuming the session started {}",state.started);
+    let mut config = config.clone();
+    config.plan.path = state.plan.clone();
+    config.plan.script = state.script.clone();
+    let (plan, script) = load_plan(&config);
+    let mut rig = open_rig(&config,false);
+    rig.previous_fixture = Some(state.fixture.clone());
+    let mut devices = discover_or_exit(&config,&rig);
+    devices.retain(|device| state.fixture.slot_for_port(&device.get_port_name()).is_some());
+    enrol(&config,&mut rig,&mut devices,&EnrolArgs { no_prompt: true, ..Default::default() });
+    devices.retain_mut(|device|{
+        match state.get_progress(device.get_serial()){
+            Some(progress) if progress.quarantined => {
+                log::warn!("Device {} was quarantined in the interrupted session; leaving it out.",progress.serial);
+                return false;
+            },
+            Some(progress) => {
+                device.resume_from(progress);
+                return true;
+            },
+            None => {
+                log::warn!("Device {} was not part of the interrupted session; leaving it out.",device.get_serial());
+                return false;
             }
-            for thread in iteration_threads{
-                thread.join().unwrap();
+        }
+    });
+    for progress in state.devices.iter(){
+        if !devices.iter_mut().any(|device| device.get_serial() == progress.serial){
+            log::warn!("Device {} from the interrupted session was not found.",progress.serial);
+        }
+    }
+    let deadline = match state.deadline{
+        Some(ref deadline) => match DateTime::parse_from_rfc3339(deadline){
+            Ok(deadline) => Some(deadline.with_timezone(&Local)),
+            Err(error) => {
+                log::error!("Invalid deadline {} in session file: {}",deadline,error);
+                process::exit(1);
             }
+        },
+        None => None,
+    };
+    let length = RunLength {
+        iterations: state.iterations,
+        schedule: Schedule { deadline, windows: state.windows.clone() },
+    };
+    let session = match Session::start(&config.session.path,state){
+        Ok(session) => session,
+        Err(error) => {
+            log::error!("{}",error);
+            process::exit(1);
         }
-        Err(_)=>{
-            log::error!("Invalid serial location! Please make sure that /dev/serial/by-path exists.");
+    };
+    run_iterations(&config,&rig,devices,&plan,script.as_ref(),&length,session);
+}
+
+fn main(){
+    let cli = Cli::parse();
+    setup_logs();
+    log::info!("Seymour Life Testing version: {}",VERSION);
+    let config = match cli.load_config(){
+        Ok(config) => config,
+        Err(error) => {
+            log::error!("{}",error);
+            process::exit(1);
         }
+    };
+    match cli.command{
+        Some(CliCommand::Scan) => scan(&config),
+        Some(CliCommand::Report) => report(),
+        Some(CliCommand::Reset{ref serial}) => {
+            //Not while a run may be writing the same file.
+            let _instance_lock = lock_instance(&config);
+            match device::reset_counts(serial){
+                Ok(_) => log::info!("Reset the totals for {}",serial),
+                Err(error) => {
+                    log::error!("{}",error);
+                    process::exit(1);
+                }
+            }
+        },
+        Some(CliCommand::Identify(ref enrol_args)) => {
+            let mut rig = open_rig(&config,enrol_args.re_enrol);
+            let mut devices = discover_or_exit(&config,&rig);
+            enrol(&config,&mut rig,&mut devices,enrol_args);
+        },
+        Some(CliCommand::Run(ref run_args)) => run(&config,run_args),
+        Some(CliCommand::Resume) => resume(&config),
+        None => run(&config,&RunArgs::default()),
     }
 }
 
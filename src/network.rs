@@ -0,0 +1,552 @@
+use std::{io::{self, Read, Write}, net::{TcpStream, ToSocketAddrs}, time::{Duration, Instant}};
+use serialport::{SerialPort, DataBits, Parity, StopBits, FlowControl, ClearBuffer, ErrorKind};
+use crate::tty::SerialSettings;
+
+const RAW_PREFIX: &str = "tcp://";
+const RFC2217_PREFIX: &str = "rfc2217://";
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+//Telnet bytes, RFC 854
+const IAC: u8 = 255;
+const DONT: u8 = 254;
+const DO: u8 = 253;
+const WONT: u8 = 252;
+const WILL: u8 = 251;
+const SB: u8 = 250;
+const SE: u8 = 240;
+const BINARY: u8 = 0;
+const SUPPRESS_GO_AHEAD: u8 = 3;
+//RFC 2217 com port control
+const COM_PORT_OPTION: u8 = 44;
+const SET_BAUDRATE: u8 = 1;
+const SET_DATASIZE: u8 = 2;
+const SET_PARITY: u8 = 3;
+const SET_STOPSIZE: u8 = 4;
+const SET_CONTROL: u8 = 5;
+const PURGE_DATA: u8 = 12;
+//Added to a command to make the server's reply to it.
+const SERVER_REPLY: u8 = 100;
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Protocol{
+    Raw,
+    Rfc2217,
+}
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+enum TelnetState{
+    Data,
+    Iac,
+    Negotiation(u8),
+    Subnegotiation,
+    SubnegotiationIac,
+}
+
+pub fn is_network_location(location:&str) -> bool{
+    return parse_location(location).is_some();
+}
+
+//Accepts tcp://host:port, rfc2217://host:port, or a bare host:port (raw TCP).
+//Anything starting with '/' is treated as a local device path.
+pub fn parse_location(location:&str) -> Option<(Protocol,&str)>{
+    if let Some(address) = location.strip_prefix(RAW_PREFIX){
+        return Some((Protocol::Raw,address));
+    }
+    if let Some(address) = location.strip_prefix(RFC2217_PREFIX){
+        return Some((Protocol::Rfc2217,address));
+    }
+    if !location.starts_with('/') && location.rsplit_once(':').is_some_and(|(_,port)| port.parse::<u16>().is_ok()){
+        return Some((Protocol::Raw,location));
+    }
+    return None;
+}
+
+//A serial console reached over TCP, as served by ser2net or a console server.
+//Implements SerialPort so that TTY can use it in place of a local port.
+pub struct NetworkPort{
+    stream: TcpStream,
+    location: String,
+    protocol: Protocol,
+    settings: SerialSettings,
+    telnet_state: TelnetState,
+    subnegotiation: Vec<u8>,
+    //Serial data read while waiting for the server, returned by the next read.
+    pending: Vec<u8>,
+    acknowledged_baud_rate: Option<u32>,
+}
+
+impl std::fmt::Debug for NetworkPort{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result{
+        f.debug_struct("NetworkPort")
+        .field("Location",&self.location)
+        .field("Protocol",&self.protocol)
+        .finish()
+    }
+}
+
+impl NetworkPort{
+    pub fn open(location:&str, settings:&SerialSettings) -> serialport::Result<Self>{
+        let (protocol,address) = parse_location(location)
+            .ok_or(serialport::Error::new(ErrorKind::InvalidInput,format!("Not a network location: {}",location)))?;
+        let socket_address = address.to_socket_addrs()?.next()
+            .ok_or(serialport::Error::new(ErrorKind::NoDevice,format!("Unable to resolve {}",address)))?;
+        let stream = TcpStream::connect_timeout(&socket_address, CONNECT_TIMEOUT)?;
+        stream.set_read_timeout(Some(settings.timeout))?;
+        stream.set_nodelay(true)?;
+        let mut output = NetworkPort{
+            stream,
+            location: location.to_string(),
+            protocol,
+            settings: settings.clone(),
+            telnet_state: TelnetState::Data,
+            subnegotiation: Vec::new(),
+            pending: Vec::new(),
+            acknowledged_baud_rate: None,
+        };
+        if protocol == Protocol::Rfc2217{
+            output.negotiate()?;
+        }
+        return Ok(output);
+    }
+
+    fn negotiate(&mut self) -> serialport::Result<()>{
+        self.stream.write_all(&[
+            IAC, WILL, COM_PORT_OPTION,
+            IAC, WILL, BINARY,
+            IAC, DO, BINARY,
+            IAC, WILL, SUPPRESS_GO_AHEAD,
+            IAC, DO, SUPPRESS_GO_AHEAD,
+        ])?;
+        self.send_baud_rate(self.settings.baud_rate)?;
+        self.send_data_bits(self.settings.data_bits)?;
+        self.send_parity(self.settings.parity)?;
+        self.send_stop_bits(self.settings.stop_bits)?;
+        self.send_flow_control(self.settings.flow_control)?;
+        return Ok(());
+    }
+
+    fn send_com_port_option(&mut self, command:u8, value:&[u8]) -> io::Result<()>{
+        if self.protocol != Protocol::Rfc2217{
+            return Ok(());
+        }
+        let mut message = vec![IAC, SB, COM_PORT_OPTION, command];
+        message.extend_from_slice(&escape(value));
+        message.extend_from_slice(&[IAC, SE]);
+        log::trace!("Sending RFC 2217 option {:?} to {}",message,self.location);
+        return self.stream.write_all(&message);
+    }
+
+    fn send_baud_rate(&mut self, baud_rate:u32) -> io::Result<()>{
+        self.send_com_port_option(SET_BAUDRATE, &baud_rate.to_be_bytes())?;
+        if self.protocol != Protocol::Rfc2217{
+            return Ok(());
+        }
+        return self.confirm_baud_rate(baud_rate);
+    }
+
+    //The server answers with the rate it actually set. A server that never
+    //answers is tolerated; one that set a different rate is not.
+    fn confirm_baud_rate(&mut self, baud_rate:u32) -> io::Result<()>{
+        self.acknowledged_baud_rate = None;
+        let deadline = Instant::now() + self.settings.timeout;
+        let mut raw = [0u8; 256];
+        while self.acknowledged_baud_rate.is_none() && Instant::now() < deadline{
+            let read_count = match self.stream.read(&mut raw){
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof,format!("{} closed the connection",self.location))),
+                Ok(read_count) => read_count,
+                Err(error) if matches!(error.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => break,
+                Err(error) => return Err(error),
+            };
+            let mut data = Vec::new();
+            self.filter_telnet(&raw[..read_count], &mut data)?;
+            self.pending.extend_from_slice(&data);
+        }
+        match self.acknowledged_baud_rate{
+            Some(acknowledged) if acknowledged == baud_rate => return Ok(()),
+            Some(acknowledged) => return Err(io::Error::other(format!("{} set the baud rate to {} instead of {}",self.location,acknowledged,baud_rate))),
+            None => {
+                log::warn!("{} did not acknowledge baud rate {}",self.location,baud_rate);
+                return Ok(());
+            }
+        }
+    }
+
+    fn send_data_bits(&mut self, data_bits:DataBits) -> io::Result<()>{
+        let value = match data_bits{
+            DataBits::Five => 5,
+            DataBits::Six => 6,
+            DataBits::Seven => 7,
+            DataBits::Eight => 8,
+        };
+        return self.send_com_port_option(SET_DATASIZE, &[value]);
+    }
+
+    fn send_parity(&mut self, parity:Parity) -> io::Result<()>{
+        let value = match parity{
+            Parity::None => 1,
+            Parity::Odd => 2,
+            Parity::Even => 3,
+        };
+        return self.send_com_port_option(SET_PARITY, &[value]);
+    }
+
+    fn send_stop_bits(&mut self, stop_bits:StopBits) -> io::Result<()>{
+        let value = match stop_bits{
+            StopBits::One => 1,
+            StopBits::Two => 2,
+        };
+        return self.send_com_port_option(SET_STOPSIZE, &[value]);
+    }
+
+    fn send_flow_control(&mut self, flow_control:FlowControl) -> io::Result<()>{
+        let value = match flow_control{
+            FlowControl::None => 1,
+            FlowControl::Software => 2,
+            FlowControl::Hardware => 3,
+        };
+        return self.send_com_port_option(SET_CONTROL, &[value]);
+    }
+
+    //Strips telnet commands out of the raw stream, refusing any option we did not ask for.
+    fn filter_telnet(&mut self, raw:&[u8], output:&mut Vec<u8>) -> io::Result<()>{
+        let mut replies:Vec<u8> = Vec::new();
+        for &byte in raw{
+            self.telnet_state = match self.telnet_state{
+                TelnetState::Data => {
+                    if byte == IAC { TelnetState::Iac }
+                    else { output.push(byte); TelnetState::Data }
+                },
+                TelnetState::Iac => match byte{
+                    IAC => { output.push(IAC); TelnetState::Data },
+                    DO | DONT | WILL | WONT => TelnetState::Negotiation(byte),
+                    SB => { self.subnegotiation.clear(); TelnetState::Subnegotiation },
+                    _ => TelnetState::Data,
+                },
+                TelnetState::Negotiation(command) => {
+                    let accepted = [BINARY, SUPPRESS_GO_AHEAD, COM_PORT_OPTION].contains(&byte);
+                    match command{
+                        DO if !accepted => replies.extend_from_slice(&[IAC, WONT, byte]),
+                        WILL if !accepted => replies.extend_from_slice(&[IAC, DONT, byte]),
+                        _ => {},
+                    }
+                    TelnetState::Data
+                },
+                TelnetState::Subnegotiation => {
+                    if byte == IAC { TelnetState::SubnegotiationIac }
+                    else { self.subnegotiation.push(byte); TelnetState::Subnegotiation }
+                },
+                TelnetState::SubnegotiationIac => {
+                    if byte == SE { self.handle_subnegotiation(); TelnetState::Data }
+                    else { self.subnegotiation.push(byte); TelnetState::Subnegotiation }
+                },
+            };
+        }
+        if !replies.is_empty(){
+            self.stream.write_all(&replies)?;
+        }
+        return Ok(());
+    }
+
+    fn handle_subnegotiation(&mut self){
+        log::trace!("Received RFC 2217 option {:?} from {}",self.subnegotiation,self.location);
+        if let [COM_PORT_OPTION, command, b0, b1, b2, b3] = self.subnegotiation[..]{
+            if command == SET_BAUDRATE + SERVER_REPLY{
+                self.acknowledged_baud_rate = Some(u32::from_be_bytes([b0, b1, b2, b3]));
+            }
+        }
+    }
+}
+
+fn escape(data:&[u8]) -> Vec<u8>{
+    let mut output = Vec::with_capacity(data.len());
+    for &byte in data{
+        output.push(byte);
+        if byte == IAC{
+            output.push(IAC);
+        }
+    }
+    return output;
+}
+
+impl Read for NetworkPort{
+    fn read(&mut self, buf:&mut [u8]) -> io::Result<usize>{
+        if !self.pending.is_empty(){
+            let count = buf.len().min(self.pending.len());
+            buf[..count].copy_from_slice(&self.pending[..count]);
+            self.pending.drain(..count);
+            return Ok(count);
+        }
+        if self.protocol == Protocol::Raw{
+            return self.stream.read(buf);
+        }
+        let mut raw = vec![0u8; buf.len()];
+        loop{
+            let read_count = self.stream.read(&mut raw)?;
+            if read_count == 0{
+                return Ok(0);
+            }
+            let mut data = Vec::with_capacity(read_count);
+            self.filter_telnet(&raw[..read_count], &mut data)?;
+            //A read made only of telnet commands must not look like end-of-stream.
+            if !data.is_empty(){
+                buf[..data.len()].copy_from_slice(&data);
+                return Ok(data.len());
+            }
+        }
+    }
+}
+
+impl Write for NetworkPort{
+    fn write(&mut self, buf:&[u8]) -> io::Result<usize>{
+        match self.protocol{
+            Protocol::Raw => return self.stream.write(buf),
+            Protocol::Rfc2217 => {
+                self.stream.write_all(&escape(buf))?;
+                return Ok(buf.len());
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()>{
+        return self.stream.flush();
+    }
+}
+
+impl SerialPort for NetworkPort{
+    fn name(&self) -> Option<String>{
+        return Some(self.location.clone());
+    }
+    fn baud_rate(&self) -> serialport::Result<u32>{
+        return Ok(self.settings.baud_rate);
+    }
+    fn data_bits(&self) -> serialport::Result<DataBits>{
+        return Ok(self.settings.data_bits);
+    }
+    fn flow_control(&self) -> serialport::Result<FlowControl>{
+        return Ok(self.settings.flow_control);
+    }
+    fn parity(&self) -> serialport::Result<Parity>{
+        return Ok(self.settings.parity);
+    }
+    fn stop_bits(&self) -> serialport::Result<StopBits>{
+        return Ok(self.settings.stop_bits);
+    }
+    fn timeout(&self) -> Duration{
+        return self.settings.timeout;
+    }
+    fn set_baud_rate(&mut self, baud_rate:u32) -> serialport::Result<()>{
+        self.send_baud_rate(baud_rate)?;
+        self.settings.baud_rate = baud_rate;
+        return Ok(());
+    }
+    fn set_data_bits(&mut self, data_bits:DataBits) -> serialport::Result<()>{
+        self.send_data_bits(data_bits)?;
+        self.settings.data_bits = data_bits;
+        return Ok(());
+    }
+    fn set_flow_control(&mut self, flow_control:FlowControl) -> serialport::Result<()>{
+        self.send_flow_control(flow_control)?;
+        self.settings.flow_control = flow_control;
+        return Ok(());
+    }
+    fn set_parity(&mut self, parity:Parity) -> serialport::Result<()>{
+        self.send_parity(parity)?;
+        self.settings.parity = parity;
+        return Ok(());
+    }
+    fn set_stop_bits(&mut self, stop_bits:StopBits) -> serialport::Result<()>{
+        self.send_stop_bits(stop_bits)?;
+        self.settings.stop_bits = stop_bits;
+        return Ok(());
+    }
+    fn set_timeout(&mut self, timeout:Duration) -> serialport::Result<()>{
+        self.stream.set_read_timeout(Some(timeout))?;
+        self.settings.timeout = timeout;
+        return Ok(());
+    }
+    //Modem control lines are not forwarded; report them as inactive.
+    fn write_request_to_send(&mut self, _level:bool) -> serialport::Result<()>{
+        return Ok(());
+    }
+    fn write_data_terminal_ready(&mut self, _level:bool) -> serialport::Result<()>{
+        return Ok(());
+    }
+    fn read_clear_to_send(&mut self) -> serialport::Result<bool>{
+        return Ok(false);
+    }
+    fn read_data_set_ready(&mut self) -> serialport::Result<bool>{
+        return Ok(false);
+    }
+    fn read_ring_indicator(&mut self) -> serialport::Result<bool>{
+        return Ok(false);
+    }
+    fn read_carrier_detect(&mut self) -> serialport::Result<bool>{
+        return Ok(false);
+    }
+    fn bytes_to_read(&self) -> serialport::Result<u32>{
+        return Ok(0);
+    }
+    fn bytes_to_write(&self) -> serialport::Result<u32>{
+        return Ok(0);
+    }
+    fn clear(&self, buffer_to_clear:ClearBuffer) -> serialport::Result<()>{
+        if self.protocol != Protocol::Rfc2217{
+            return Ok(());
+        }
+        let value = match buffer_to_clear{
+            ClearBuffer::Input => 1,
+            ClearBuffer::Output => 2,
+            ClearBuffer::All => 3,
+        };
+        let mut stream = self.stream.try_clone()?;
+        stream.write_all(&[IAC, SB, COM_PORT_OPTION, PURGE_DATA, value, IAC, SE])?;
+        return Ok(());
+    }
+    fn try_clone(&self) -> serialport::Result<Box<dyn SerialPort>>{
+        return Ok(Box::new(NetworkPort{
+            stream: self.stream.try_clone()?,
+            location: self.location.clone(),
+            protocol: self.protocol,
+            settings: self.settings.clone(),
+            telnet_state: TelnetState::Data,
+            subnegotiation: Vec::new(),
+            pending: Vec::new(),
+            acknowledged_baud_rate: None,
+        }));
+    }
+    fn set_break(&self) -> serialport::Result<()>{
+        return Err(serialport::Error::new(ErrorKind::Unknown,"Break is not supported on network ports"));
+    }
+    fn clear_break(&self) -> serialport::Result<()>{
+        return Err(serialport::Error::new(ErrorKind::Unknown,"Break is not supported on network ports"));
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use std::{net::TcpListener, thread::{self, JoinHandle}};
+
+    fn settings() -> SerialSettings{
+        return SerialSettings { timeout: Duration::from_millis(500), lock_directory: None, ..Default::default() };
+    }
+
+    //A console server stand-in that runs serve on the first connection.
+    fn serve<F:FnOnce(TcpStream) + Send + 'static>(serve:F) -> (String, JoinHandle<()>){
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move ||{
+            let (stream, _) = listener.accept().unwrap();
+            serve(stream);
+        });
+        return (address, server);
+    }
+
+    //Reads until the stream has sent needle, returning everything read.
+    fn read_until(stream:&mut TcpStream, needle:&[u8]) -> Vec<u8>{
+        let mut received = Vec::new();
+        let mut byte = [0u8];
+        while !received.ends_with(needle){
+            if stream.read(&mut byte).unwrap() == 0{
+                break;
+            }
+            received.push(byte[0]);
+        }
+        return received;
+    }
+
+    fn baud_rate_request(baud_rate:u32) -> Vec<u8>{
+        let mut request = vec![IAC, SB, COM_PORT_OPTION, SET_BAUDRATE];
+        request.extend_from_slice(&escape(&baud_rate.to_be_bytes()));
+        request.extend_from_slice(&[IAC, SE]);
+        return request;
+    }
+
+    fn baud_rate_reply(baud_rate:u32) -> Vec<u8>{
+        let mut reply = vec![IAC, SB, COM_PORT_OPTION, SET_BAUDRATE + SERVER_REPLY];
+        reply.extend_from_slice(&escape(&baud_rate.to_be_bytes()));
+        reply.extend_from_slice(&[IAC, SE]);
+        return reply;
+    }
+
+    #[test]
+    fn locations(){
+        assert_eq!(parse_location("tcp://rack:4001"),Some((Protocol::Raw,"rack:4001")));
+        assert_eq!(parse_location("rfc2217://rack:4001"),Some((Protocol::Rfc2217,"rack:4001")));
+        assert_eq!(parse_location("10.0.0.5:4001"),Some((Protocol::Raw,"10.0.0.5:4001")));
+        assert_eq!(parse_location("/dev/ttyUSB0"),None);
+        assert_eq!(parse_location("rack:console"),None);
+    }
+
+    #[test]
+    fn raw_passes_bytes_through(){
+        let (address, server) = serve(|mut stream|{
+            assert_eq!(read_until(&mut stream,b"\n"),b"root\n");
+            stream.write_all(&[b'#', IAC]).unwrap();
+        });
+        let mut port = NetworkPort::open(&format!("tcp://{}",address),&settings()).unwrap();
+        port.write_all(b"root\n").unwrap();
+        let mut received = [0u8; 2];
+        port.read_exact(&mut received).unwrap();
+        assert_eq!(received,[b'#', IAC]);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn rfc2217_waits_for_the_baud_rate_and_keeps_data_sent_meanwhile(){
+        let (address, server) = serve(|mut stream|{
+            read_until(&mut stream,&baud_rate_request(115200));
+            let mut reply = vec![IAC, DO, COM_PORT_OPTION, b'o', b'k', IAC, IAC];
+            reply.extend_from_slice(&baud_rate_reply(115200));
+            stream.write_all(&reply).unwrap();
+            //The rest of the negotiation follows without waiting for replies.
+            read_until(&mut stream,&[IAC, SB, COM_PORT_OPTION, SET_CONTROL, 1, IAC, SE]);
+            assert_eq!(read_until(&mut stream,&[IAC, IAC]),[b'x', IAC, IAC]);
+        });
+        let mut port = NetworkPort::open(&format!("rfc2217://{}",address),&SerialSettings { baud_rate: 115200, ..settings() }).unwrap();
+        let mut received = [0u8; 3];
+        port.read_exact(&mut received).unwrap();
+        assert_eq!(received,[b'o', b'k', IAC]);
+        port.write_all(&[b'x', IAC]).unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn rfc2217_refuses_a_different_baud_rate(){
+        let (address, server) = serve(|mut stream|{
+            read_until(&mut stream,&baud_rate_request(115200));
+            stream.write_all(&baud_rate_reply(9600)).unwrap();
+        });
+        let result = NetworkPort::open(&format!("rfc2217://{}",address),&SerialSettings { baud_rate: 115200, ..settings() });
+        assert!(result.is_err());
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn rfc2217_tolerates_a_server_that_never_acknowledges(){
+        let (address, server) = serve(|mut stream|{
+            read_until(&mut stream,&[IAC, SB, COM_PORT_OPTION, SET_CONTROL, 1, IAC, SE]);
+        });
+        assert!(NetworkPort::open(&format!("rfc2217://{}",address),&settings()).is_ok());
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn rfc2217_refuses_options_it_did_not_ask_for(){
+        const TERMINAL_TYPE:u8 = 24;
+        let (address, server) = serve(|mut stream|{
+            read_until(&mut stream,&baud_rate_request(115200));
+            let mut reply = vec![IAC, DO, TERMINAL_TYPE, b'!'];
+            reply.extend_from_slice(&baud_rate_reply(115200));
+            stream.write_all(&reply).unwrap();
+            read_until(&mut stream,&[IAC, WONT, TERMINAL_TYPE]);
+            read_until(&mut stream,&[IAC, SB, COM_PORT_OPTION, SET_CONTROL, 1, IAC, SE]);
+        });
+        let mut port = NetworkPort::open(&format!("rfc2217://{}",address),&SerialSettings { baud_rate: 115200, ..settings() }).unwrap();
+        let mut received = [0u8; 1];
+        port.read_exact(&mut received).unwrap();
+        assert_eq!(received,[b'!']);
+        server.join().unwrap();
+    }
+
+}
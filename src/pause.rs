@@ -0,0 +1,179 @@
+use std::{sync::{Arc, Condvar, Mutex}, thread::{self, JoinHandle}, time::{Duration, Instant}};
+use chrono::Local;
+use crate::gpio_facade::{InputLine, RelayAllocator};
+
+pub const DEFAULT_DEBOUNCE:Duration = Duration::from_millis(50);
+const POLL_INTERVAL:Duration = Duration::from_millis(10);
+
+//Shared between the input watcher and every device thread. Devices only look at
+//it between steps, so a pause never interrupts a half-finished command.
+#[derive(Clone,Debug,Default)]
+pub struct PauseSignal{
+    state: Arc<(Mutex<bool>, Condvar)>,
+    //Released by the watcher as soon as the input activates, without waiting
+    //for devices to reach a safe point.
+    relays: Arc<Mutex<Vec<Arc<RelayAllocator>>>>,
+}
+
+impl PauseSignal{
+    pub fn new() -> Self{
+        return PauseSignal::default();
+    }
+
+    pub fn pause(&self){
+        let (lock, _) = &*self.state;
+        if let Ok(mut paused) = lock.lock(){
+            *paused = true;
+        }
+    }
+
+    pub fn resume(&self){
+        let (lock, condvar) = &*self.state;
+        if let Ok(mut paused) = lock.lock(){
+            *paused = false;
+        }
+        condvar.notify_all();
+    }
+
+    pub fn release_on_pause(&self, relays:Arc<RelayAllocator>){
+        if let Ok(mut all_relays) = self.relays.lock(){
+            all_relays.push(relays);
+        }
+    }
+
+    fn release_relays(&self){
+        let all_relays = self.relays.lock().map(|all_relays| all_relays.clone()).unwrap_or_default();
+        for relays in all_relays.iter(){
+            relays.release_all();
+        }
+    }
+
+    pub fn is_paused(&self) -> bool{
+        let (lock, _) = &*self.state;
+        return lock.lock().map(|paused| *paused).unwrap_or(false);
+    }
+
+    //Blocks until resumed. Returns immediately if not paused.
+    pub fn wait_while_paused(&self){
+        let (lock, condvar) = &*self.state;
+        if let Ok(paused) = lock.lock(){
+            let _resumed = condvar.wait_while(paused, |paused| *paused);
+        }
+    }
+}
+
+#[derive(Clone,Debug)]
+pub struct PauseSettings{
+    pub address: u8,
+    //A switch to ground with the line pulled up reads low when pressed.
+    pub active_low: bool,
+    pub pull_up: bool,
+    pub debounce: Duration,
+}
+
+//Polls the input and only acts on a level that has held for the debounce time,
+//so contact bounce on the switch doesn't toggle the rack. Relays are released
+//on activation; devices then hold at their next safe point.
+pub fn watch_input(line:Box<dyn InputLine>, settings:PauseSettings, signal:PauseSignal) -> JoinHandle<()>{
+    return thread::spawn(move ||{
+        let is_active = |line:&dyn InputLine| line.is_high() != settings.active_low;
+        let mut active = is_active(line.as_ref());
+        if active{
+            log::warn!("Pause input on GPIO {} is already active at {}; pausing.",line.get_address(),Local::now().to_rfc3339());
+            signal.pause();
+            signal.release_relays();
+        }
+        let mut changed_since: Option<Instant> = None;
+        loop{
+            thread::sleep(POLL_INTERVAL);
+            if is_active(line.as_ref()) == active{
+                changed_since = None;
+                continue;
+            }
+            let since = *changed_since.get_or_insert(Instant::now());
+            if since.elapsed() < settings.debounce{
+                continue;
+            }
+            changed_since = None;
+            active = !active;
+            if active{
+                log::warn!("Pause input on GPIO {} activated at {}; pausing all devices.",line.get_address(),Local::now().to_rfc3339());
+                signal.pause();
+                signal.release_relays();
+            }
+            else{
+                log::warn!("Pause input on GPIO {} released at {}; resuming.",line.get_address(),Local::now().to_rfc3339());
+                signal.resume();
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::gpio_facade::{GpioBackend, MockBackend, RelaySettings};
+
+    const INPUT:u8 = 21;
+
+    fn watch(backend:&MockBackend, active_low:bool) -> PauseSignal{
+        let settings = PauseSettings { address: INPUT, active_low, pull_up: active_low, debounce: DEFAULT_DEBOUNCE };
+        let signal = PauseSignal::new();
+        watch_input(backend.input(INPUT,settings.pull_up).unwrap(),settings,signal.clone());
+        //Let the watcher read the starting level first.
+        thread::sleep(POLL_INTERVAL * 2);
+        return signal;
+    }
+
+    #[test]
+    fn bounces_shorter_than_the_debounce_are_ignored(){
+        let backend = MockBackend::new();
+        let signal = watch(&backend,false);
+        //Each level lasts a couple of polls, so the watcher sees every bounce.
+        for _ in 0..5{
+            backend.set_input(INPUT,true);
+            thread::sleep(POLL_INTERVAL * 2);
+            backend.set_input(INPUT,false);
+            thread::sleep(POLL_INTERVAL * 3);
+        }
+        assert!(!signal.is_paused());
+    }
+
+    #[test]
+    fn a_held_input_pauses_and_releasing_it_resumes(){
+        let backend = MockBackend::new();
+        let signal = watch(&backend,false);
+        backend.set_input(INPUT,true);
+        thread::sleep(DEFAULT_DEBOUNCE * 4);
+        assert!(signal.is_paused());
+        let waiter = {
+            let signal = signal.clone();
+            thread::spawn(move || signal.wait_while_paused())
+        };
+        backend.set_input(INPUT,false);
+        thread::sleep(DEFAULT_DEBOUNCE * 4);
+        assert!(!signal.is_paused());
+        waiter.join().unwrap();
+    }
+
+    #[test]
+    fn an_active_low_input_already_active_pauses_at_once(){
+        let backend = MockBackend::new();
+        let signal = watch(&backend,true);
+        assert!(signal.is_paused());
+    }
+
+    #[test]
+    fn relays_are_released_as_soon_as_the_input_activates(){
+        let backend = MockBackend::new();
+        let relays = RelayAllocator::new(Arc::new(backend.clone()),RelaySettings { addresses: vec![4], ..Default::default() });
+        let mut lease = relays.lease(4).unwrap();
+        lease.energise();
+        let signal = watch(&backend,false);
+        signal.release_on_pause(relays.clone());
+        backend.set_input(INPUT,true);
+        thread::sleep(DEFAULT_DEBOUNCE * 4);
+        assert!(signal.is_paused());
+        assert!(!lease.is_energised());
+    }
+}
@@ -0,0 +1,212 @@
+use std::{fs, sync::Arc};
+use serde::Deserialize;
+use crate::{step::{self, StepRegistry, TestStep, BpStep, TempStep, RebootStep, WaitStep}, target::Targets, retry::RetryPolicy};
+
+pub const DEFAULT_BOOT_WAIT_S: u64 = 60;
+//Steps that leave the unit in its menu. The shell can only be reached from
+//there by quitting the menu, which reboots the unit.
+const MENU_STEPS:[&str;3] = [step::BP, step::TEMP, step::BRIGHTNESS];
+
+fn default_repeat() -> u64{
+    return 1;
+}
+
+//Retries as written on a step. Steps retry on any failure, so unlike a
+//command's policy there are no replies to list.
+#[derive(Clone,Debug,Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct StepRetry{
+    attempts: u32,
+    backoff_ms: u64,
+}
+
+impl Default for StepRetry{
+    fn default() -> Self {
+        let policy = RetryPolicy::default();
+        return StepRetry { attempts: policy.attempts, backoff_ms: policy.backoff_ms };
+    }
+}
+
+impl From<StepRetry> for RetryPolicy{
+    fn from(retry:StepRetry) -> Self {
+        return RetryPolicy { attempts: retry.attempts, backoff_ms: retry.backoff_ms, ..Default::default() };
+    }
+}
+
+//A step as written in a plan file. Everything besides kind, repeat and retry
+//is handed to the step kind to interpret.
+#[derive(Clone,Debug,Deserialize)]
+struct StepEntry{
+    kind: String,
+    #[serde(default = "default_repeat")]
+    repeat: u64,
+    #[serde(default)]
+    retry: StepRetry,
+    #[serde(flatten)]
+    options: toml::Table,
+}
+
+#[derive(Clone,Debug,Deserialize)]
+struct PlanFile{
+    name: Option<String>,
+    steps: Vec<StepEntry>,
+    #[serde(default)]
+    targets: Targets,
+}
+
+#[derive(Clone,Debug)]
+pub struct PlannedStep{
+    pub step: Arc<dyn TestStep>,
+    pub repeat: u64,
+    //Applies to each repeat on its own.
+    pub retry: RetryPolicy,
+}
+
+impl PlannedStep{
+    pub fn new(step:Arc<dyn TestStep>, repeat:u64) -> Self{
+        return PlannedStep { step, repeat, retry: RetryPolicy::default() };
+    }
+}
+
+//The steps of one life-test cycle, run in order on every device.
+#[derive(Clone,Debug)]
+pub struct TestPlan{
+    pub name: Option<String>,
+    pub steps: Vec<PlannedStep>,
+    //Lifetime totals every unit on this plan runs to, unless the config sets its own.
+    pub targets: Targets,
+}
+
+impl Default for TestPlan{
+    fn default() -> Self {
+        return TestPlan::standard_cycle(3,2);
+    }
+}
+
+impl TestPlan{
+    pub fn load(path:&str) -> Result<Self,String>{
+        return TestPlan::load_with(path,&StepRegistry::default());
+    }
+
+    //Loads a plan that may use step kinds added to the registry by another crate.
+    pub fn load_with(path:&str, registry:&StepRegistry) -> Result<Self,String>{
+        let contents = fs::read_to_string(path).map_err(|error| format!("Unable to read test plan {}: {}",path,error))?;
+        let file:PlanFile = toml::from_str(&contents).map_err(|error| format!("Unable to parse test plan {}: {}",path,error))?;
+        return TestPlan::build(file,registry).map_err(|error| format!("Invalid test plan {}: {}",path,error));
+    }
+
+    fn build(file:PlanFile, registry:&StepRegistry) -> Result<Self,String>{
+        if file.steps.is_empty(){
+            return Err("the plan has no steps".to_string());
+        }
+        let mut steps = Vec::new();
+        //Every cycle starts at the login prompt.
+        let mut menu_step:Option<(usize,String)> = None;
+        for (index, entry) in file.steps.into_iter().enumerate(){
+            if entry.repeat == 0{
+                return Err(format!("step {} ({}): repeat must be at least 1",index+1,entry.kind));
+            }
+            if entry.kind == step::COMMAND{
+                if let Some((menu_index, ref menu_kind)) = menu_step{
+                    return Err(format!("step {} (command): step {} ({}) leaves the unit in its menu, and reaching the shell from there reboots it; add a reboot step between them",index+1,menu_index+1,menu_kind));
+                }
+            }
+            if MENU_STEPS.contains(&entry.kind.as_str()){
+                menu_step = Some((index,entry.kind.clone()));
+            }
+            else if entry.kind == step::REBOOT || entry.kind == step::COMMAND{
+                menu_step = None;
+            }
+            let retry = RetryPolicy::from(entry.retry);
+            retry.validate().map_err(|error| format!("step {} ({}): {}",index+1,entry.kind,error))?;
+            let step = registry.build(&entry.kind,entry.options).map_err(|error| format!("step {} ({}): {}",index+1,entry.kind,error))?;
+            steps.push(PlannedStep { step, repeat: entry.repeat, retry });
+        }
+        file.targets.validate().map_err(|error| format!("targets: {}",error))?;
+        return Ok(TestPlan { name: file.name, steps, targets: file.targets });
+    }
+
+    //Boot wait, BP runs, temp runs, then a reboot: the cycle used without a plan file.
+    pub fn standard_cycle(bp_cycles:u64, temp_cycles:u64) -> Self{
+        let mut steps = vec![PlannedStep::new(Arc::new(WaitStep { seconds: DEFAULT_BOOT_WAIT_S }),1)];
+        if bp_cycles > 0{
+            steps.push(PlannedStep::new(Arc::new(BpStep::default()),bp_cycles));
+        }
+        if temp_cycles > 0{
+            steps.push(PlannedStep::new(Arc::new(TempStep::default()),temp_cycles));
+        }
+        steps.push(PlannedStep::new(Arc::new(RebootStep::default()),1));
+        return TestPlan { name: None, steps, targets: Targets::default() };
+    }
+
+    pub fn has_step(&self, name:&str) -> bool{
+        return self.steps.iter().any(|planned| planned.step.get_name() == name);
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn parse(text:&str) -> Result<TestPlan,String>{
+        let file:PlanFile = toml::from_str(text).map_err(|error| error.to_string())?;
+        return TestPlan::build(file,&StepRegistry::default());
+    }
+
+    fn describe(plan:&TestPlan) -> Vec<String>{
+        return plan.steps.iter().map(|planned| format!("{:?} x{}",planned.step,planned.repeat)).collect();
+    }
+
+    #[test]
+    fn a_plan_reads_its_steps_in_order(){
+        let plan = parse("name = \"soak\"\n[[steps]]\nkind = \"bp\"\nrun_s = 30\nrepeat = 2\n[[steps]]\nkind = \"reboot\"\n[steps.retry]\nattempts = 3\n").unwrap();
+        assert_eq!(plan.name.as_deref(),Some("soak"));
+        assert_eq!(describe(&plan),vec!["BpStep { run_s: 30 } x2","RebootStep { timeout_s: 120 } x1"]);
+        assert_eq!(plan.steps[0].retry,RetryPolicy::default());
+        assert_eq!(plan.steps[1].retry.attempts,3);
+        assert!(plan.has_step(step::REBOOT));
+        assert!(!plan.has_step(step::TEMP));
+    }
+
+    #[test]
+    fn an_empty_plan_is_rejected(){
+        assert!(parse("steps = []").unwrap_err().contains("no steps"));
+    }
+
+    #[test]
+    fn a_step_repeated_zero_times_is_rejected(){
+        let error = parse("[[steps]]\nkind = \"temp\"\nrepeat = 0\n").unwrap_err();
+        assert!(error.contains("step 1 (temp): repeat must be at least 1"),"{}",error);
+    }
+
+    #[test]
+    fn a_command_after_a_menu_step_needs_a_reboot_between(){
+        let error = parse("[[steps]]\nkind = \"bp\"\n[[steps]]\nkind = \"wait\"\nseconds = 1\n[[steps]]\nkind = \"command\"\ncommand = \"uptime\"\n").unwrap_err();
+        assert!(error.contains("step 3 (command): step 1 (bp) leaves the unit in its menu"),"{}",error);
+        assert!(parse("[[steps]]\nkind = \"bp\"\n[[steps]]\nkind = \"reboot\"\n[[steps]]\nkind = \"command\"\ncommand = \"uptime\"\n").is_ok());
+        assert!(parse("[[steps]]\nkind = \"command\"\ncommand = \"uptime\"\n[[steps]]\nkind = \"bp\"\n").is_ok());
+    }
+
+    #[test]
+    fn unknown_step_kinds_and_options_are_rejected(){
+        let error = parse("[[steps]]\nkind = \"dance\"\n").unwrap_err();
+        assert!(error.contains("step 1 (dance): unknown step kind dance"),"{}",error);
+        let error = parse("[[steps]]\nkind = \"wait\"\nseconds = 1\nminutes = 2\n").unwrap_err();
+        assert!(error.contains("step 1 (wait)") && error.contains("minutes"),"{}",error);
+    }
+
+    #[test]
+    fn step_retries_take_no_reply_list(){
+        let error = parse("[[steps]]\nkind = \"temp\"\n[steps.retry]\nattempts = 2\nretry_on = [\"Empty\"]\n").unwrap_err();
+        assert!(error.contains("retry_on"),"{}",error);
+        let error = parse("[[steps]]\nkind = \"temp\"\n[steps.retry]\nattempts = 0\n").unwrap_err();
+        assert!(error.contains("step 1 (temp): retry attempts must be at least 1"),"{}",error);
+    }
+
+    #[test]
+    fn the_standard_cycle_is_the_one_run_without_a_plan_file(){
+        assert_eq!(describe(&TestPlan::standard_cycle(3,2)),vec!["WaitStep { seconds: 60 } x1","BpStep { run_s: 75 } x3","TempStep x2","RebootStep { timeout_s: 120 } x1"]);
+        assert_eq!(describe(&TestPlan::standard_cycle(0,0)),vec!["WaitStep { seconds: 60 } x1","RebootStep { timeout_s: 120 } x1"]);
+        assert_eq!(describe(&TestPlan::default()),describe(&TestPlan::standard_cycle(3,2)));
+    }
+}
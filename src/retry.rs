@@ -0,0 +1,84 @@
+use std::time::Duration;
+use serde::Deserialize;
+use crate::tty::Response;
+
+pub const DEFAULT_BACKOFF:Duration = Duration::from_millis(500);
+
+//How often to try something again before believing it failed. The default
+//tries once, so nothing is retried unless configured.
+#[derive(Clone,Debug,Deserialize,PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryPolicy{
+    //Tries in all, counting the first.
+    pub attempts: u32,
+    //Wait before the first retry, doubled for each one after it.
+    pub backoff_ms: u64,
+    //Replies that mean the command was probably lost on the link, so it is
+    //worth sending again. Only commands have this; steps retry on any failure.
+    pub retry_on: Vec<Response>,
+}
+
+impl Default for RetryPolicy{
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: 1,
+            backoff_ms: DEFAULT_BACKOFF.as_millis() as u64,
+            retry_on: vec![Response::Empty, Response::Other],
+        }
+    }
+}
+
+impl RetryPolicy{
+    pub fn validate(&self) -> Result<(),String>{
+        if self.attempts == 0{
+            return Err("retry attempts must be at least 1".to_string());
+        }
+        return Ok(());
+    }
+
+    //attempt counts from 1.
+    pub fn should_retry(&self, attempt:u32, response:&Response) -> bool{
+        return attempt < self.attempts && self.retry_on.contains(response);
+    }
+
+    pub fn get_delay(&self, attempt:u32) -> Duration{
+        let factor = 2u64.saturating_pow(attempt.saturating_sub(1));
+        return Duration::from_millis(self.backoff_ms.saturating_mul(factor));
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn default_tries_once(){
+        let policy = RetryPolicy::default();
+        assert!(policy.validate().is_ok());
+        assert!(!policy.should_retry(1,&Response::Empty));
+    }
+
+    #[test]
+    fn retries_listed_responses_until_attempts_run_out(){
+        let policy = RetryPolicy { attempts: 3, ..Default::default() };
+        assert!(policy.should_retry(1,&Response::Empty));
+        assert!(policy.should_retry(2,&Response::Other));
+        assert!(!policy.should_retry(3,&Response::Empty));
+        assert!(!policy.should_retry(1,&Response::BPOn));
+    }
+
+    #[test]
+    fn delay_doubles_from_the_backoff(){
+        let policy = RetryPolicy { attempts: 4, backoff_ms: 100, ..Default::default() };
+        assert_eq!(policy.get_delay(1),Duration::from_millis(100));
+        assert_eq!(policy.get_delay(2),Duration::from_millis(200));
+        assert_eq!(policy.get_delay(3),Duration::from_millis(400));
+        assert_eq!(policy.get_delay(200),Duration::from_millis(u64::MAX));
+    }
+
+    #[test]
+    fn zero_attempts_is_invalid(){
+        let policy = RetryPolicy { attempts: 0, ..Default::default() };
+        assert!(policy.validate().is_err());
+    }
+}
@@ -0,0 +1,218 @@
+use std::{fmt, time::Duration};
+use chrono::{DateTime, Local, NaiveTime};
+use serde::{Deserialize, Deserializer, Serialize};
+use crate::shutdown::ShutdownSignal;
+
+const WINDOW_POLL:Duration = Duration::from_secs(10);
+
+//Reads a run length such as 90s, 45m, 72h or 3d. A bare number is seconds.
+pub fn parse_duration(text:&str) -> Result<Duration,String>{
+    let text = text.trim();
+    let (number, unit) = match text.find(|character:char| !character.is_ascii_digit()){
+        Some(index) => text.split_at(index),
+        None => (text, "s"),
+    };
+    let number:u64 = number.parse().map_err(|_| format!("Expected a duration such as 72h, got {}",text))?;
+    let multiplier:u64 = match unit{
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(format!("Unknown unit {} in duration {}; use s, m, h or d",unit,text)),
+    };
+    let seconds = number.checked_mul(multiplier).ok_or(format!("Duration {} is too long",text))?;
+    return Ok(Duration::from_secs(seconds));
+}
+
+pub fn deserialize_duration<'de, D:Deserializer<'de>>(deserializer:D) -> Result<Option<Duration>,D::Error>{
+    let text:Option<String> = Option::deserialize(deserializer)?;
+    match text{
+        Some(text) => return parse_duration(&text).map(Some).map_err(serde::de::Error::custom),
+        None => return Ok(None),
+    }
+}
+
+//A daily stretch of time when devices may run, e.g. 18:00-07:00. A window
+//whose end is before its start runs overnight.
+#[derive(Clone,Copy,Debug,Deserialize,Serialize,PartialEq)]
+#[serde(try_from = "String", into = "String")]
+pub struct Window{
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl Window{
+    pub fn parse(text:&str) -> Result<Self,String>{
+        let (start, end) = text.split_once('-').ok_or(format!("Expected a window such as 18:00-07:00, got {}",text))?;
+        let parse_time = |time:&str| NaiveTime::parse_from_str(time.trim(),"%H:%M").map_err(|error| format!("Invalid time {} in window {}: {}",time,text,error));
+        let window = Window { start: parse_time(start)?, end: parse_time(end)? };
+        //An empty window would never let the run start.
+        if window.start == window.end{
+            return Err(format!("Window {} starts and ends at the same time; give no windows to run all day",text));
+        }
+        return Ok(window);
+    }
+
+    pub fn contains(&self, time:NaiveTime) -> bool{
+        if self.start <= self.end{
+            return self.start <= time && time < self.end;
+        }
+        return time >= self.start || time < self.end;
+    }
+}
+
+impl TryFrom<String> for Window{
+    type Error = String;
+    fn try_from(text:String) -> Result<Self,String>{
+        return Window::parse(&text);
+    }
+}
+
+impl From<Window> for String{
+    fn from(window:Window) -> String{
+        return window.to_string();
+    }
+}
+
+impl fmt::Display for Window{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        write!(f,"{}-{}",self.start.format("%H:%M"),self.end.format("%H:%M"))
+    }
+}
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum ScheduleState{
+    Open,
+    //Outside every window; wait for the next one.
+    Closed,
+    Over,
+}
+
+//When a run may go on: until an optional deadline, and only inside the
+//windows if any are given. Checked between cycles, never partway through one.
+#[derive(Clone,Debug,Default)]
+pub struct Schedule{
+    pub deadline: Option<DateTime<Local>>,
+    pub windows: Vec<Window>,
+}
+
+impl Schedule{
+    //A schedule whose duration, if any, counts from now.
+    pub fn starting_now(duration:Option<Duration>, windows:Vec<Window>) -> Self{
+        let deadline = duration.and_then(|duration| chrono::Duration::from_std(duration).ok()).map(|duration| Local::now() + duration);
+        return Schedule { deadline, windows };
+    }
+
+    pub fn is_over(&self) -> bool{
+        return self.deadline.is_some_and(|deadline| Local::now() >= deadline);
+    }
+
+    pub fn in_window(&self) -> bool{
+        let now = Local::now().time();
+        return self.windows.is_empty() || self.windows.iter().any(|window| window.contains(now));
+    }
+
+    //The deadline wins over the windows, so a run whose time is up while
+    //outside every window still ends.
+    pub fn get_state(&self) -> ScheduleState{
+        if self.is_over(){
+            return ScheduleState::Over;
+        }
+        if !self.in_window(){
+            return ScheduleState::Closed;
+        }
+        return ScheduleState::Open;
+    }
+
+    //Sleeps until a window opens, the deadline passes or the run is stopped.
+    pub fn wait_for_window(&self, shutdown:&ShutdownSignal){
+        while !self.is_over() && !self.in_window(){
+            if !shutdown.sleep(WINDOW_POLL){
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn at(hour:u32, minute:u32) -> NaiveTime{
+        return NaiveTime::from_hms_opt(hour,minute,0).unwrap();
+    }
+
+    #[test]
+    fn daytime_window_includes_start_but_not_end(){
+        let window = Window::parse("09:00-17:30").unwrap();
+        assert!(window.contains(at(9,0)));
+        assert!(window.contains(at(12,0)));
+        assert!(!window.contains(at(17,30)));
+        assert!(!window.contains(at(8,59)));
+    }
+
+    #[test]
+    fn overnight_window_wraps_past_midnight(){
+        let window = Window::parse("18:00-07:00").unwrap();
+        assert!(window.contains(at(18,0)));
+        assert!(window.contains(at(23,59)));
+        assert!(window.contains(at(0,0)));
+        assert!(window.contains(at(6,59)));
+        assert!(!window.contains(at(7,0)));
+        assert!(!window.contains(at(12,0)));
+    }
+
+    #[test]
+    fn window_rejects_bad_text(){
+        assert!(Window::parse("00:00-00:00").is_err());
+        assert!(Window::parse("18:00").is_err());
+        assert!(Window::parse("25:00-07:00").is_err());
+    }
+
+    #[test]
+    fn window_round_trips_through_text(){
+        let window = Window::parse(" 18:00 - 07:00 ").unwrap();
+        assert_eq!(window.to_string(),"18:00-07:00");
+        assert_eq!(Window::parse(&window.to_string()).unwrap(),window);
+    }
+
+    //A window an hour from now, which cannot contain the present.
+    fn later_window() -> Window{
+        let now = Local::now().time();
+        return Window { start: now + chrono::Duration::hours(1), end: now + chrono::Duration::hours(2) };
+    }
+
+    #[test]
+    fn outside_every_window_is_closed(){
+        let schedule = Schedule::starting_now(Some(Duration::from_secs(3600)),vec![later_window()]);
+        assert_eq!(schedule.get_state(),ScheduleState::Closed);
+        assert_eq!(Schedule::starting_now(None,Vec::new()).get_state(),ScheduleState::Open);
+    }
+
+    #[test]
+    fn a_deadline_passed_outside_the_windows_ends_the_run(){
+        let schedule = Schedule { deadline: Some(Local::now() - chrono::Duration::seconds(1)), windows: vec![later_window()] };
+        assert_eq!(schedule.get_state(),ScheduleState::Over);
+        let started = std::time::Instant::now();
+        schedule.wait_for_window(&ShutdownSignal::new());
+        assert!(started.elapsed() < WINDOW_POLL);
+    }
+
+    #[test]
+    fn duration_units(){
+        assert_eq!(parse_duration("90").unwrap(),Duration::from_secs(90));
+        assert_eq!(parse_duration("90s").unwrap(),Duration::from_secs(90));
+        assert_eq!(parse_duration("45m").unwrap(),Duration::from_secs(45 * 60));
+        assert_eq!(parse_duration(" 72h ").unwrap(),Duration::from_secs(72 * 60 * 60));
+        assert_eq!(parse_duration("3d").unwrap(),Duration::from_secs(3 * 24 * 60 * 60));
+    }
+
+    #[test]
+    fn duration_rejects_bad_text(){
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("3w").is_err());
+        assert!(parse_duration("-3h").is_err());
+        assert!(parse_duration(&format!("{}d",u64::MAX / 1000)).is_err());
+    }
+}
@@ -0,0 +1,249 @@
+use std::{fs, sync::{Arc, Mutex, MutexGuard, atomic::{AtomicBool, Ordering}}, time::Duration};
+use rhai::{Dynamic, Engine, EvalAltResult, AST};
+use crate::{device::{Device, BP_TESTS, TEMP_TESTS, REBOOTS, HARD_REBOOTS}, step::DEFAULT_COMMAND_TIMEOUT};
+
+//A device handed to a script. Only the device's own thread ever locks it.
+pub type SharedDevice = Arc<Mutex<Device>>;
+
+//Ends a script caught in a runaway loop. Waiting on the device costs only a
+//few operations, so a whole cycle stays far below this.
+const MAX_OPERATIONS: u64 = 1_000_000;
+
+fn lock(device:&SharedDevice) -> MutexGuard<'_, Device>{
+    return device.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+}
+
+//A Rhai script run once per cycle in place of a test plan. Scripts drive the
+//device through the functions registered in build_engine and record their own
+//results; starting a BP, temp or reboot is a pause point, as between plan steps.
+//Reboots, power cycles and BP starts take their places with the coordinator
+//themselves; acquire("kind") and release("kind") take a place for any other
+//step kind limited in the config. Places still held are given back when the
+//cycle ends.
+#[derive(Clone,Debug)]
+pub struct TestScript{
+    path: String,
+    ast: AST,
+}
+
+impl TestScript{
+    pub fn load(path:&str) -> Result<Self,String>{
+        let contents = fs::read_to_string(path).map_err(|error| format!("Unable to read script {}: {}",path,error))?;
+        let ast = Engine::new().compile(&contents).map_err(|error| format!("Unable to compile script {}: {}",path,error))?;
+        return Ok(TestScript { path: path.to_string(), ast });
+    }
+
+    //Builds the engine and its bindings for one device, once for the whole run.
+    pub fn bind(&self, device:&SharedDevice) -> DeviceScript{
+        let failed = Arc::new(AtomicBool::new(false));
+        let engine = build_engine(device,&failed);
+        return DeviceScript { script: self.clone(), device: device.clone(), engine, failed };
+    }
+}
+
+pub struct DeviceScript{
+    script: TestScript,
+    device: SharedDevice,
+    engine: Engine,
+    failed: Arc<AtomicBool>,
+}
+
+impl DeviceScript{
+    //Runs one cycle. A script error or a call to fail() marks the device as
+    //failing. A script stopped by a shutdown leaves the cycle unfinished.
+    pub fn run_cycle(&self) -> bool{
+        let device = &self.device;
+        if !lock(device).start_cycle(){
+            return false;
+        }
+        self.failed.store(false,Ordering::SeqCst);
+        let result = self.engine.run_ast(&self.script.ast);
+        if lock(device).is_stopping(){
+            log::info!("Script {} stopped on device {} by shutdown",self.script.path,lock(device).get_serial());
+            return false;
+        }
+        if let Err(error) = result{
+            log::warn!("Script {} stopped on device {}: {}",self.script.path,lock(device).get_serial(),error);
+            self.failed.store(true,Ordering::SeqCst);
+        }
+        let failed = self.failed.load(Ordering::SeqCst);
+        let mut device = lock(device);
+        device.finish_cycle(failed);
+        device.note_result("script",!failed);
+        return !failed;
+    }
+}
+
+fn build_engine(device:&SharedDevice, failed:&Arc<AtomicBool>) -> Engine{
+    let mut engine = Engine::new();
+    engine.set_max_operations(MAX_OPERATIONS);
+    let serial = lock(device).get_serial().to_string();
+
+    let print_serial = serial.clone();
+    engine.on_print(move |text| log::info!("[{}] {}",print_serial,text));
+    let debug_serial = serial.clone();
+    engine.on_debug(move |text, _, _| log::debug!("[{}] {}",debug_serial,text));
+
+    let shared = device.clone();
+    engine.on_progress(move |_| match lock(&shared).is_stopping(){
+        true => Some(Dynamic::UNIT),
+        false => None,
+    });
+
+    let shared = device.clone();
+    engine.register_fn("serial", move || lock(&shared).get_serial().to_string());
+    let shared = device.clone();
+    engine.register_fn("has_relay", move || lock(&shared).get_pin_address().is_some());
+    let shared = device.clone();
+    engine.register_fn("has_power_relay", move || lock(&shared).has_power_relay());
+
+    let shared = device.clone();
+    engine.register_fn("start_bp", move ||{
+        let mut device = lock(&shared);
+        if device.wait_if_paused(){
+            device.start_bp();
+        }
+    });
+    let shared = device.clone();
+    engine.register_fn("is_bp_running", move || lock(&shared).is_bp_running());
+    let shared = device.clone();
+    engine.register_fn("start_temp", move ||{
+        let mut device = lock(&shared);
+        if device.wait_if_paused(){
+            device.start_temp();
+        }
+    });
+    let shared = device.clone();
+    engine.register_fn("stop_temp", move ||{
+        lock(&shared).stop_temp();
+    });
+    let shared = device.clone();
+    engine.register_fn("is_temp_running", move || lock(&shared).is_temp_running());
+    let shared = device.clone();
+    engine.register_fn("reboot", move ||{
+        let mut device = lock(&shared);
+        if device.wait_if_paused(){
+            device.reboot();
+        }
+    });
+    let shared = device.clone();
+    engine.register_fn("power_cycle", move || lock(&shared).power_cycle());
+    let shared = device.clone();
+    engine.register_fn("brighten_screen", move ||{
+        lock(&shared).brighten_screen();
+    });
+    let shared = device.clone();
+    engine.register_fn("darken_screen", move ||{
+        lock(&shared).darken_screen();
+    });
+    let shared = device.clone();
+    engine.register_fn("run_command", move |command:&str| -> Result<String,Box<EvalAltResult>>{
+        match lock(&shared).run_shell_command(command,DEFAULT_COMMAND_TIMEOUT){
+            Some(output) => return Ok(output),
+            None => return Err(format!("Command {} did not finish",command).into()),
+        }
+    });
+    let shared = device.clone();
+    engine.register_fn("wait", move |seconds:i64|{
+        lock(&shared).sleep(Duration::from_secs(seconds.max(0) as u64));
+    });
+
+    let shared = device.clone();
+    engine.register_fn("record", move |label:&str| lock(&shared).record(label) as i64);
+    let shared = device.clone();
+    engine.register_fn("record_bp", move || lock(&shared).record(BP_TESTS) as i64);
+    let shared = device.clone();
+    engine.register_fn("record_temp", move || lock(&shared).record(TEMP_TESTS) as i64);
+    let shared = device.clone();
+    engine.register_fn("record_reboot", move || lock(&shared).record(REBOOTS) as i64);
+    let shared = device.clone();
+    engine.register_fn("record_power_cycle", move || lock(&shared).record(HARD_REBOOTS) as i64);
+    let shared = device.clone();
+    engine.register_fn("count", move |label:&str| lock(&shared).get_count(label) as i64);
+
+    let shared = device.clone();
+    engine.register_fn("acquire", move |operation:&str| lock(&shared).hold(operation));
+    let shared = device.clone();
+    engine.register_fn("release", move |operation:&str| lock(&shared).let_go(operation));
+    let failed = failed.clone();
+    engine.register_fn("fail", move |reason:&str|{
+        log::warn!("[{}] {}",serial,reason);
+        failed.store(true,Ordering::SeqCst);
+    });
+    return engine;
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use std::{thread, time::Instant};
+    use crate::shutdown::ShutdownSignal;
+    use crate::test_unit::TestUnit;
+
+    fn write_script(name:&str, source:&str) -> String{
+        let path = format!("target/test-output/{}.rhai",name);
+        fs::create_dir_all("target/test-output").unwrap();
+        fs::write(&path,source).unwrap();
+        return path;
+    }
+
+    fn bound(unit:&TestUnit, name:&str, source:&str) -> (SharedDevice, DeviceScript){
+        let script = TestScript::load(&write_script(name,source)).unwrap();
+        let device = Arc::new(Mutex::new(unit.device()));
+        let device_script = script.bind(&device);
+        return (device, device_script);
+    }
+
+    #[test]
+    fn a_script_that_will_not_compile_is_refused_at_load(){
+        let error = TestScript::load(&write_script("bad-syntax","let x = ;")).unwrap_err();
+        assert!(error.starts_with("Unable to compile script"),"{}",error);
+        assert!(TestScript::load("target/test-output/missing.rhai").unwrap_err().starts_with("Unable to read script"));
+    }
+
+    #[test]
+    fn bindings_drive_the_unit_and_record_into_its_counts(){
+        let unit = TestUnit::start("script-record");
+        let (device, device_script) = bound(&unit,"script-record","start_bp();\nif is_bp_running() { record_bp(); }\nrecord(\"Chimes\");\nif count(\"Chimes\") != 1 { fail(\"miscounted\"); }\n");
+        assert!(device_script.run_cycle());
+        let device = lock(&device);
+        assert_eq!(device.get_count(BP_TESTS),1);
+        assert_eq!(device.get_count("Chimes"),1);
+        assert_eq!(device.get_cycles(),1);
+        assert!(unit.get_received().contains(&"N".to_string()));
+    }
+
+    #[test]
+    fn fail_marks_only_the_cycle_it_was_called_in(){
+        let unit = TestUnit::start("script-fail");
+        let (device, device_script) = bound(&unit,"script-fail","if count(\"Cycles\") == 0 { fail(\"first cycle\"); }\nrecord(\"Cycles\");\n");
+        assert!(!device_script.run_cycle());
+        assert!(device_script.run_cycle());
+        assert_eq!(lock(&device).get_cycles(),2);
+    }
+
+    #[test]
+    fn a_runaway_loop_is_stopped_and_fails_the_cycle(){
+        let unit = TestUnit::start("script-runaway");
+        let (device, device_script) = bound(&unit,"script-runaway","loop {}");
+        assert!(!device_script.run_cycle());
+        assert_eq!(lock(&device).get_cycles(),1);
+    }
+
+    #[test]
+    fn a_shutdown_stops_a_script_and_leaves_its_cycle_unfinished(){
+        let unit = TestUnit::start("script-shutdown");
+        let (device, device_script) = bound(&unit,"script-shutdown","loop { wait(1); }");
+        let shutdown = ShutdownSignal::new();
+        lock(&device).set_shutdown_signal(shutdown.clone());
+        let requester = shutdown.clone();
+        thread::spawn(move ||{
+            thread::sleep(Duration::from_millis(100));
+            requester.request();
+        });
+        let started = Instant::now();
+        assert!(!device_script.run_cycle());
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(lock(&device).get_cycles(),0);
+    }
+}
@@ -0,0 +1,174 @@
+use std::{fs, path::Path, sync::{Arc, Mutex}};
+use serde::{Deserialize, Serialize};
+use crate::{device::Counts, fixture::FixtureMap, schedule::Window};
+
+pub const DEFAULT_SESSION_FILE: &str = "session.toml";
+
+//Where a unit is within its current cycle: the next plan step to run and how
+//many of that step's repeats are already done.
+#[derive(Clone,Copy,Debug,Default,Deserialize,Serialize,PartialEq)]
+pub struct CyclePosition{
+    pub step: usize,
+    pub repeat: u64,
+}
+
+#[derive(Clone,Debug,Deserialize,Serialize)]
+pub struct DeviceProgress{
+    pub serial: String,
+    //Cycles finished in this session.
+    pub cycles: u64,
+    //Totals as they stood at the position below. Anything recorded after it
+    //is discarded on resume and run again, so nothing is counted twice.
+    pub counts: Counts,
+    //Taken out of the run by its failure policy; resume leaves it out.
+    #[serde(default)]
+    pub quarantined: bool,
+    //Set while a cycle is under way.
+    pub position: Option<CyclePosition>,
+}
+
+//Everything needed to rebuild an interrupted run without asking the operator again.
+#[derive(Clone,Debug,Deserialize,Serialize)]
+pub struct SessionState{
+    pub started: String,
+    pub plan: Option<String>,
+    pub script: Option<String>,
+    pub iterations: Option<u64>,
+    //RFC 3339 time after which no new cycles start.
+    pub deadline: Option<String>,
+    #[serde(default)]
+    pub windows: Vec<Window>,
+    pub fixture: FixtureMap,
+    pub devices: Vec<DeviceProgress>,
+}
+
+impl SessionState{
+    pub fn get_progress(&self, serial:&str) -> Option<&DeviceProgress>{
+        return self.devices.iter().find(|progress| progress.serial == serial);
+    }
+}
+
+//The checkpoint file of a run in progress. Device threads update it as they
+//go; it is removed once the run finishes.
+#[derive(Debug)]
+pub struct Session{
+    path: String,
+    state: Mutex<SessionState>,
+}
+
+impl Session{
+    pub fn load(path:&str) -> Result<SessionState,String>{
+        if !Path::new(path).is_file(){
+            return Err(format!("No interrupted session to resume ({} not found)",path));
+        }
+        let contents = fs::read_to_string(path).map_err(|error| format!("Unable to read session file {}: {}",path,error))?;
+        return toml::from_str(&contents).map_err(|error| format!("Unable to parse session file {}: {}",path,error));
+    }
+
+    pub fn start(path:&str, state:SessionState) -> Result<Arc<Self>,String>{
+        save(path,&state)?;
+        return Ok(Arc::new(Session { path: path.to_string(), state: Mutex::new(state) }));
+    }
+
+    pub fn update(&self, progress:DeviceProgress){
+        let mut state = self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        match state.devices.iter_mut().find(|entry| entry.serial == progress.serial){
+            Some(entry) => *entry = progress,
+            None => state.devices.push(progress),
+        }
+        //Saved while still locked so two devices never write the file at once.
+        if let Err(error) = save(&self.path,&state){
+            log::warn!("Unable to save session checkpoint.");
+            log::debug!("{}",error);
+        }
+    }
+
+    pub fn finish(&self){
+        if let Err(error) = fs::remove_file(&self.path){
+            log::warn!("Unable to remove session file {}",self.path);
+            log::debug!("{}",error);
+        }
+    }
+}
+
+fn save(path:&str, state:&SessionState) -> Result<(),String>{
+    let contents = toml::to_string(state).map_err(|error| format!("Unable to serialise session: {}",error))?;
+    let temp_path = path.to_owned() + ".tmp";
+    fs::write(&temp_path, contents).map_err(|error| format!("Unable to write session file {}: {}",temp_path,error))?;
+    return fs::rename(&temp_path, path).map_err(|error| format!("Unable to write session file {}: {}",path,error));
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::device::BP_TESTS;
+
+    fn session_path(name:&str) -> String{
+        fs::create_dir_all("target/test-output").unwrap();
+        let path = format!("target/test-output/{}-session.toml",name);
+        _ = fs::remove_file(&path);
+        return path;
+    }
+
+    fn progress(serial:&str, cycles:u64, position:Option<CyclePosition>) -> DeviceProgress{
+        let mut counts = Counts::default();
+        counts.set(BP_TESTS,cycles*3);
+        return DeviceProgress { serial: serial.to_string(), cycles, counts, quarantined: false, position };
+    }
+
+    fn state() -> SessionState{
+        let mut fixture = FixtureMap::default();
+        fixture.record("/dev/serial/by-path/port-a",Some(4),"A1");
+        return SessionState {
+            started: "2026-10-19T08:00:00+00:00".to_string(),
+            plan: Some("plan.toml".to_string()),
+            script: None,
+            iterations: Some(50),
+            deadline: None,
+            windows: Vec::new(),
+            fixture,
+            devices: vec![progress("A1",0,None)],
+        };
+    }
+
+    #[test]
+    fn a_checkpoint_loads_back_as_it_was_saved(){
+        let path = session_path("round-trip");
+        let session = Session::start(&path,state()).unwrap();
+        session.update(progress("A1",7,Some(CyclePosition { step: 2, repeat: 1 })));
+        session.update(DeviceProgress { quarantined: true, ..progress("B2",3,None) });
+        let loaded = Session::load(&path).unwrap();
+        assert_eq!(loaded.plan.as_deref(),Some("plan.toml"));
+        assert_eq!(loaded.iterations,Some(50));
+        assert_eq!(loaded.fixture.slot_for_serial("A1").and_then(|slot| slot.relay),Some(4));
+        let first = loaded.get_progress("A1").unwrap();
+        assert_eq!(first.cycles,7);
+        assert_eq!(first.counts.get(BP_TESTS),21);
+        assert_eq!(first.position,Some(CyclePosition { step: 2, repeat: 1 }));
+        let second = loaded.get_progress("B2").unwrap();
+        assert!(second.quarantined);
+        assert_eq!(second.position,None);
+        assert_eq!(loaded.devices.len(),2);
+    }
+
+    #[test]
+    fn each_checkpoint_replaces_the_file_whole(){
+        let path = session_path("replace");
+        let temp_path = path.clone() + ".tmp";
+        fs::write(&temp_path,"left by a write that never finished").unwrap();
+        let session = Session::start(&path,state()).unwrap();
+        assert!(!Path::new(&temp_path).exists());
+        for cycles in 1..=5{
+            session.update(progress("A1",cycles,None));
+            assert_eq!(Session::load(&path).unwrap().get_progress("A1").unwrap().cycles,cycles);
+            assert!(!Path::new(&temp_path).exists());
+        }
+    }
+
+    #[test]
+    fn a_finished_session_leaves_nothing_to_resume(){
+        let path = session_path("finished");
+        Session::start(&path,state()).unwrap().finish();
+        assert!(Session::load(&path).unwrap_err().starts_with("No interrupted session to resume"));
+    }
+}
@@ -0,0 +1,167 @@
+use std::{process, sync::{atomic::{AtomicBool, Ordering}, Arc, Condvar, Mutex}, time::{Duration, Instant}};
+use crate::gpio_facade::RelayAllocator;
+
+pub const DEFAULT_GRACE:Duration = Duration::from_secs(60);
+//Stopped by a signal with every device at a safe point; resume carries on from here.
+pub const EXIT_STOPPED:i32 = 3;
+//Stopped by a signal before every device reached a safe point.
+pub const EXIT_UNCLEAN:i32 = 4;
+
+//Set by SIGINT or SIGTERM. Devices finish the command in hand, then stop
+//taking on new steps; sleeps through it are cut short.
+#[derive(Clone,Debug,Default)]
+pub struct ShutdownSignal{
+    state: Arc<(Mutex<bool>, Condvar)>,
+    //Released when stopping without waiting for devices.
+    relays: Arc<Mutex<Vec<Arc<RelayAllocator>>>>,
+    //Set once devices are running and stop at safe points. Before then, e.g.
+    //during enrolment, nothing would notice a request.
+    safe_points: Arc<AtomicBool>,
+}
+
+impl ShutdownSignal{
+    pub fn new() -> Self{
+        return ShutdownSignal::default();
+    }
+
+    pub fn request(&self){
+        let (lock, condvar) = &*self.state;
+        if let Ok(mut requested) = lock.lock(){
+            *requested = true;
+        }
+        condvar.notify_all();
+    }
+
+    pub fn release_on_stop(&self, relays:Arc<RelayAllocator>){
+        if let Ok(mut all_relays) = self.relays.lock(){
+            all_relays.push(relays);
+        }
+    }
+
+    pub fn release_relays(&self){
+        let all_relays = self.relays.lock().map(|all_relays| all_relays.clone()).unwrap_or_default();
+        for relays in all_relays.iter(){
+            relays.release_all();
+        }
+    }
+
+    pub fn stop_at_safe_points(&self){
+        self.safe_points.store(true,Ordering::SeqCst);
+    }
+
+    pub fn is_requested(&self) -> bool{
+        let (lock, _) = &*self.state;
+        return lock.lock().map(|requested| *requested).unwrap_or(false);
+    }
+
+    pub fn wait_for_request(&self){
+        let (lock, condvar) = &*self.state;
+        if let Ok(requested) = lock.lock(){
+            let _requested = condvar.wait_while(requested, |requested| !*requested);
+        }
+    }
+
+    //Sleeps for the duration, or until shutdown is requested. False if cut short.
+    pub fn sleep(&self, duration:Duration) -> bool{
+        let (lock, condvar) = &*self.state;
+        let deadline = Instant::now() + duration;
+        let mut requested = match lock.lock(){
+            Ok(requested) => requested,
+            Err(_) => return false,
+        };
+        while !*requested{
+            let now = Instant::now();
+            if now >= deadline{
+                return true;
+            }
+            requested = match condvar.wait_timeout(requested, deadline - now){
+                Ok((requested, _)) => requested,
+                Err(_) => return false,
+            };
+        }
+        return false;
+    }
+}
+
+//Before devices are running any SIGINT or SIGTERM releases every relay and
+//exits. After that, the first asks devices to stop at a safe point and a
+//second releases every relay and exits straight away.
+pub fn install(signal:ShutdownSignal) -> Result<(),String>{
+    return ctrlc::set_handler(move ||{
+        if !signal.safe_points.load(Ordering::SeqCst){
+            log::warn!("Stopping; releasing every relay.");
+        }
+        else if signal.is_requested(){
+            log::warn!("Stopping now; devices may be left partway through a command.");
+        }
+        else{
+            log::warn!("Stop requested; waiting for devices to reach a safe point. Send again to stop now.");
+            signal.request();
+            return;
+        }
+        signal.release_relays();
+        log::logger().flush();
+        process::exit(EXIT_UNCLEAN);
+    }).map_err(|error| format!("Unable to handle stop signals: {}",error));
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use std::thread;
+    use crate::gpio_facade::{MockBackend, RelaySettings};
+
+    fn request_after(signal:&ShutdownSignal, delay:Duration){
+        let signal = signal.clone();
+        thread::spawn(move ||{
+            thread::sleep(delay);
+            signal.request();
+        });
+    }
+
+    #[test]
+    fn a_sleep_runs_its_course_without_a_request(){
+        let signal = ShutdownSignal::new();
+        let started = Instant::now();
+        assert!(signal.sleep(Duration::from_millis(30)));
+        assert!(started.elapsed() >= Duration::from_millis(30));
+        assert!(!signal.is_requested());
+    }
+
+    #[test]
+    fn a_request_cuts_a_sleep_short(){
+        let signal = ShutdownSignal::new();
+        request_after(&signal,Duration::from_millis(20));
+        let started = Instant::now();
+        assert!(!signal.sleep(Duration::from_secs(10)));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(!signal.sleep(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn waiting_ends_once_a_clone_is_requested(){
+        let signal = ShutdownSignal::new();
+        request_after(&signal.clone(),Duration::from_millis(20));
+        signal.wait_for_request();
+        assert!(signal.is_requested());
+    }
+
+    #[test]
+    fn every_registered_set_of_relays_is_released(){
+        let backend = Arc::new(MockBackend::new());
+        let temp_relays = RelayAllocator::new(backend.clone(),RelaySettings { addresses: vec![5], ..Default::default() });
+        let power_relays = RelayAllocator::new(backend.clone(),RelaySettings { addresses: vec![6], ..Default::default() });
+        let mut temp = temp_relays.lease(5).unwrap();
+        let mut power = power_relays.lease(6).unwrap();
+        temp.energise();
+        power.energise();
+        let signal = ShutdownSignal::new();
+        signal.release_on_stop(temp_relays.clone());
+        signal.clone().release_on_stop(power_relays.clone());
+        signal.release_relays();
+        assert!(!temp.is_energised());
+        assert!(!power.is_energised());
+        assert!(!backend.is_high(5));
+        assert!(!backend.is_high(6));
+    }
+}
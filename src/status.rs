@@ -0,0 +1,300 @@
+use std::{collections::{HashMap, HashSet}, sync::{Arc, Mutex}, thread::{self, JoinHandle}, time::Duration};
+use crate::{gpio_facade::{GpioBackend, OutputLine}, pause::PauseSignal};
+
+pub const DEFAULT_BLINK_INTERVAL:Duration = Duration::from_millis(500);
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum RunState{
+    Starting,
+    WaitingForOperator,
+    Running,
+}
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+enum Lamp{
+    Off,
+    On,
+    Blink,
+}
+
+#[derive(Debug)]
+struct BoardState{
+    run_state: RunState,
+    pause: Option<PauseSignal>,
+    failing: HashSet<String>,
+    retired: HashSet<String>,
+    attention_port: Option<String>,
+}
+
+//What the lights should show. Devices and main update it; the light thread
+//only ever reads it, so a slow serial port never stalls the lamps.
+#[derive(Debug)]
+pub struct StatusBoard{
+    state: Mutex<BoardState>,
+}
+
+impl StatusBoard{
+    pub fn new() -> Arc<Self>{
+        return Arc::new(StatusBoard { state: Mutex::new(BoardState {
+            run_state: RunState::Starting,
+            pause: None,
+            failing: HashSet::new(),
+            retired: HashSet::new(),
+            attention_port: None,
+        })});
+    }
+
+    pub fn set_run_state(&self, run_state:RunState){
+        if let Ok(mut state) = self.state.lock(){
+            state.run_state = run_state;
+        }
+    }
+
+    pub fn set_pause_signal(&self, pause:PauseSignal){
+        if let Ok(mut state) = self.state.lock(){
+            state.pause = Some(pause);
+        }
+    }
+
+    pub fn set_failing(&self, port:&str, failing:bool){
+        if let Ok(mut state) = self.state.lock(){
+            match failing{
+                true => state.failing.insert(port.to_string()),
+                false => state.failing.remove(port),
+            };
+        }
+    }
+
+    //A unit that has met its targets; its slot LED goes dark.
+    pub fn set_retired(&self, port:&str){
+        if let Ok(mut state) = self.state.lock(){
+            state.retired.insert(port.to_string());
+        }
+    }
+
+    //Points the operator at one slot, e.g. the unit whose serial is being asked for.
+    pub fn set_attention_port(&self, port:Option<&str>){
+        if let Ok(mut state) = self.state.lock(){
+            state.attention_port = port.map(|port| port.to_string());
+        }
+    }
+
+    //Green: running. Amber: starting up or paused, blinking when the operator
+    //is needed. Red: at least one unit failing.
+    fn get_lamps(&self) -> (Lamp, Lamp, Lamp){
+        let state = match self.state.lock(){
+            Ok(state) => state,
+            Err(_) => return (Lamp::Off, Lamp::Off, Lamp::On),
+        };
+        let paused = state.pause.as_ref().is_some_and(|pause| pause.is_paused());
+        let green = match state.run_state{
+            RunState::Running if !paused => Lamp::On,
+            _ => Lamp::Off,
+        };
+        let amber = match state.run_state{
+            RunState::WaitingForOperator => Lamp::Blink,
+            _ if paused => Lamp::On,
+            RunState::Starting => Lamp::On,
+            _ => Lamp::Off,
+        };
+        let red = match state.failing.is_empty(){
+            true => Lamp::Off,
+            false => Lamp::On,
+        };
+        return (green, amber, red);
+    }
+
+    //Per-slot LEDs: lit while the unit runs cleanly, blinking when it is failing
+    //or needs the operator, dark once it is retired.
+    fn get_slot_lamp(&self, port:&str) -> Lamp{
+        let state = match self.state.lock(){
+            Ok(state) => state,
+            Err(_) => return Lamp::Off,
+        };
+        if state.failing.contains(port) || state.attention_port.as_deref() == Some(port){
+            return Lamp::Blink;
+        }
+        if state.retired.contains(port){
+            return Lamp::Off;
+        }
+        if state.run_state == RunState::Running{
+            return Lamp::On;
+        }
+        return Lamp::Off;
+    }
+}
+
+#[derive(Clone,Debug)]
+pub struct StatusSettings{
+    pub green: Option<u8>,
+    pub amber: Option<u8>,
+    pub red: Option<u8>,
+    pub active_low: bool,
+    pub blink_interval: Duration,
+}
+
+impl Default for StatusSettings{
+    fn default() -> Self {
+        StatusSettings {
+            green: None,
+            amber: None,
+            red: None,
+            active_low: false,
+            blink_interval: DEFAULT_BLINK_INTERVAL,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Light{
+    line: Box<dyn OutputLine>,
+    active_low: bool,
+}
+
+impl Light{
+    fn show(&mut self, lamp:Lamp, blink_on:bool){
+        let lit = match lamp{
+            Lamp::Off => false,
+            Lamp::On => true,
+            Lamp::Blink => blink_on,
+        };
+        if lit != self.active_low{
+            self.line.set_high();
+        }
+        else{
+            self.line.set_low();
+        }
+    }
+}
+
+fn open_light(backend:&dyn GpioBackend, address:u8, active_low:bool) -> Option<Light>{
+    match backend.output(address,active_low){
+        Ok(line) => return Some(Light { line, active_low }),
+        Err(error) => {
+            log::warn!("Unable to use GPIO {} as a status light.",address);
+            log::debug!("{}",error);
+            return None;
+        }
+    }
+}
+
+//Opens the stack light and per-slot LEDs (port to GPIO address) and keeps them
+//in step with the board. None if no lights could be opened.
+pub fn drive_lights(backend:&dyn GpioBackend, settings:StatusSettings, slot_leds:HashMap<String,u8>, board:Arc<StatusBoard>) -> Option<JoinHandle<()>>{
+    let mut green = settings.green.and_then(|address| open_light(backend,address,settings.active_low));
+    let mut amber = settings.amber.and_then(|address| open_light(backend,address,settings.active_low));
+    let mut red = settings.red.and_then(|address| open_light(backend,address,settings.active_low));
+    let mut slots:Vec<(String,Light)> = slot_leds.into_iter()
+        .filter_map(|(port,address)| Some((port,open_light(backend,address,settings.active_low)?)))
+        .collect();
+    if green.is_none() && amber.is_none() && red.is_none() && slots.is_empty(){
+        return None;
+    }
+    return Some(thread::spawn(move ||{
+        let mut blink_on = false;
+        loop{
+            blink_on = !blink_on;
+            let (green_lamp, amber_lamp, red_lamp) = board.get_lamps();
+            for (light, lamp) in [(&mut green, green_lamp), (&mut amber, amber_lamp), (&mut red, red_lamp)]{
+                if let Some(light) = light{
+                    light.show(lamp,blink_on);
+                }
+            }
+            for (port, light) in slots.iter_mut(){
+                light.show(board.get_slot_lamp(port),blink_on);
+            }
+            thread::sleep(settings.blink_interval);
+        }
+    }));
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use std::time::Instant;
+    use crate::gpio_facade::MockBackend;
+
+    const GREEN:u8 = 1;
+    const AMBER:u8 = 2;
+    const RED:u8 = 3;
+    const SLOT_A:u8 = 10;
+    const SLOT_B:u8 = 11;
+
+    fn light_board(board:&Arc<StatusBoard>) -> MockBackend{
+        let backend = MockBackend::new();
+        let settings = StatusSettings { green: Some(GREEN), amber: Some(AMBER), red: Some(RED), active_low: false, blink_interval: Duration::from_millis(5) };
+        let slot_leds = HashMap::from([("port-a".to_string(),SLOT_A),("port-b".to_string(),SLOT_B)]);
+        assert!(drive_lights(&backend,settings,slot_leds,board.clone()).is_some());
+        return backend;
+    }
+
+    //What a light did over the next few blinks, once a tick begun before the
+    //last change has had time to finish.
+    fn shown(backend:&MockBackend, address:u8) -> Lamp{
+        thread::sleep(Duration::from_millis(20));
+        let since = Instant::now();
+        thread::sleep(Duration::from_millis(60));
+        let levels:Vec<bool> = backend.get_changes().into_iter()
+            .filter(|change| change.address == address && change.time >= since)
+            .map(|change| change.high)
+            .collect();
+        match (levels.contains(&true), levels.contains(&false)){
+            (true, true) => return Lamp::Blink,
+            (true, false) => return Lamp::On,
+            _ => return Lamp::Off,
+        }
+    }
+
+    #[test]
+    fn starting_up_shows_amber(){
+        let board = StatusBoard::new();
+        let backend = light_board(&board);
+        assert_eq!(shown(&backend,GREEN),Lamp::Off);
+        assert_eq!(shown(&backend,AMBER),Lamp::On);
+        assert_eq!(shown(&backend,RED),Lamp::Off);
+        assert_eq!(shown(&backend,SLOT_A),Lamp::Off);
+    }
+
+    #[test]
+    fn a_pause_turns_green_to_amber_and_the_operator_blinks_it(){
+        let board = StatusBoard::new();
+        let pause = PauseSignal::new();
+        board.set_pause_signal(pause.clone());
+        board.set_run_state(RunState::Running);
+        let backend = light_board(&board);
+        assert_eq!(shown(&backend,GREEN),Lamp::On);
+        assert_eq!(shown(&backend,AMBER),Lamp::Off);
+        pause.pause();
+        assert_eq!(shown(&backend,GREEN),Lamp::Off);
+        assert_eq!(shown(&backend,AMBER),Lamp::On);
+        board.set_run_state(RunState::WaitingForOperator);
+        assert_eq!(shown(&backend,AMBER),Lamp::Blink);
+    }
+
+    #[test]
+    fn a_failing_unit_lights_red_and_blinks_only_its_own_slot(){
+        let board = StatusBoard::new();
+        board.set_run_state(RunState::Running);
+        let backend = light_board(&board);
+        board.set_failing("port-a",true);
+        assert_eq!(shown(&backend,RED),Lamp::On);
+        assert_eq!(shown(&backend,GREEN),Lamp::On);
+        assert_eq!(shown(&backend,SLOT_A),Lamp::Blink);
+        assert_eq!(shown(&backend,SLOT_B),Lamp::On);
+        board.set_failing("port-a",false);
+        assert_eq!(shown(&backend,RED),Lamp::Off);
+        assert_eq!(shown(&backend,SLOT_A),Lamp::On);
+    }
+
+    #[test]
+    fn a_retired_slot_goes_dark_unless_it_needs_the_operator(){
+        let board = StatusBoard::new();
+        board.set_run_state(RunState::Running);
+        let backend = light_board(&board);
+        board.set_retired("port-b");
+        assert_eq!(shown(&backend,SLOT_B),Lamp::Off);
+        board.set_attention_port(Some("port-b"));
+        assert_eq!(shown(&backend,SLOT_B),Lamp::Blink);
+        assert_eq!(shown(&backend,SLOT_A),Lamp::On);
+    }
+}
@@ -0,0 +1,448 @@
+use std::{collections::HashMap, fmt::Debug, sync::Arc, time::Duration};
+use regex::Regex;
+use serde::{de::DeserializeOwned, Deserialize};
+use crate::device::{Device, BP_TESTS, TEMP_TESTS, REBOOTS, HARD_REBOOTS};
+
+pub const BP: &str = "bp";
+pub const TEMP: &str = "temp";
+pub const BRIGHTNESS: &str = "brightness";
+pub const REBOOT: &str = "reboot";
+pub const WAIT: &str = "wait";
+pub const COMMAND: &str = "command";
+pub const DEFAULT_BP_RUN:Duration = Duration::from_secs(75);
+pub const DEFAULT_COMMAND_TIMEOUT:Duration = Duration::from_secs(10);
+pub const DEFAULT_REBOOT_TIMEOUT:Duration = Duration::from_secs(120);
+
+//What a step saw while executing, handed back to it for evaluation.
+#[derive(Clone,Debug,PartialEq)]
+pub enum Observation{
+    //A state read before and after, e.g. BP running or temp reading present.
+    Transition{ before: bool, after: bool },
+    //Text printed by the device.
+    Output(String),
+    //A measured value, for steps that read a number back.
+    Value(f64),
+    Completed(bool),
+    //The step did something other than usual, such as a power cycle in place of a reboot.
+    Substituted,
+}
+
+//One kind of life-test step. Steps are shared by every device thread, so any
+//per-run state belongs on the Device, not the step. Implement this in another
+//crate and add it to a StepRegistry to use it from test plan files.
+pub trait TestStep: Send + Sync + Debug{
+    fn get_name(&self) -> &str;
+    //Gets the device ready. Returning false skips the step without failing it.
+    fn prepare(&self, _device:&mut Device) -> bool{
+        return true;
+    }
+    fn execute(&self, device:&mut Device) -> Observation;
+    //True for a step that takes and gives back its own coordinator place
+    //rather than holding it just while it executes.
+    fn holds_own_place(&self) -> bool{
+        return false;
+    }
+    fn evaluate(&self, observation:&Observation) -> bool;
+    //The count to add one to when the step passes, if any.
+    fn get_record(&self, _observation:&Observation) -> Option<&str>{
+        return None;
+    }
+}
+
+#[derive(Clone,Debug,Deserialize,PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct BpStep{
+    #[serde(default = "default_bp_run_s")]
+    pub run_s: u64,
+}
+
+fn default_bp_run_s() -> u64{
+    return DEFAULT_BP_RUN.as_secs();
+}
+
+impl Default for BpStep{
+    fn default() -> Self {
+        return BpStep { run_s: default_bp_run_s() };
+    }
+}
+
+impl TestStep for BpStep{
+    fn get_name(&self) -> &str{
+        return BP;
+    }
+    fn prepare(&self, device:&mut Device) -> bool{
+        device.enter_lifecycle_menu();
+        let next = device.get_count(BP_TESTS)+1;
+        log::info!("Running bp {} on device {} ...",next,device.get_serial());
+        return true;
+    }
+    fn execute(&self, device:&mut Device) -> Observation{
+        device.start_bp();
+        let before = device.is_bp_running();
+        device.sleep(Duration::from_secs(self.run_s));
+        let after = device.is_bp_running();
+        return Observation::Transition { before, after };
+    }
+    //The place is kept until the BP is seen to have finished, however long
+    //that takes past this step.
+    fn holds_own_place(&self) -> bool{
+        return true;
+    }
+    fn evaluate(&self, observation:&Observation) -> bool{
+        return matches!(observation, Observation::Transition { before, after } if before != after);
+    }
+    fn get_record(&self, _observation:&Observation) -> Option<&str>{
+        return Some(BP_TESTS);
+    }
+}
+
+//Skipped on devices without a temp relay.
+#[derive(Clone,Debug,Default,Deserialize,PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TempStep{}
+
+impl TestStep for TempStep{
+    fn get_name(&self) -> &str{
+        return TEMP;
+    }
+    fn prepare(&self, device:&mut Device) -> bool{
+        if device.get_pin_address().is_none(){
+            return false;
+        }
+        device.enter_lifecycle_menu();
+        let next = device.get_count(TEMP_TESTS)+1;
+        log::info!("Running temp {} on device {} ...",next,device.get_serial());
+        return true;
+    }
+    fn execute(&self, device:&mut Device) -> Observation{
+        let before = device.start_temp().is_temp_running();
+        let after = device.stop_temp().is_temp_running();
+        return Observation::Transition { before, after };
+    }
+    fn evaluate(&self, observation:&Observation) -> bool{
+        return matches!(observation, Observation::Transition { before, after } if before != after);
+    }
+    fn get_record(&self, _observation:&Observation) -> Option<&str>{
+        return Some(TEMP_TESTS);
+    }
+}
+
+#[derive(Clone,Copy,Debug,Deserialize,PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BrightnessLevel{
+    High,
+    Low,
+}
+
+#[derive(Clone,Debug,Deserialize,PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct BrightnessStep{
+    pub level: BrightnessLevel,
+}
+
+impl TestStep for BrightnessStep{
+    fn get_name(&self) -> &str{
+        return BRIGHTNESS;
+    }
+    fn execute(&self, device:&mut Device) -> Observation{
+        match self.level{
+            BrightnessLevel::High => device.brighten_screen(),
+            BrightnessLevel::Low => device.darken_screen(),
+        };
+        return Observation::Completed(true);
+    }
+    fn evaluate(&self, observation:&Observation) -> bool{
+        return *observation == Observation::Completed(true);
+    }
+}
+
+//Power cycles instead on the cycles set by the device's power settings.
+//Passes only if the login prompt comes back within the timeout; a timeout of
+//0 sends the reboot and passes without waiting.
+#[derive(Clone,Debug,Deserialize,PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RebootStep{
+    #[serde(default = "default_reboot_timeout_s")]
+    pub timeout_s: u64,
+}
+
+fn default_reboot_timeout_s() -> u64{
+    return DEFAULT_REBOOT_TIMEOUT.as_secs();
+}
+
+impl Default for RebootStep{
+    fn default() -> Self {
+        return RebootStep { timeout_s: default_reboot_timeout_s() };
+    }
+}
+
+impl TestStep for RebootStep{
+    fn get_name(&self) -> &str{
+        return REBOOT;
+    }
+    fn execute(&self, device:&mut Device) -> Observation{
+        let substituted = device.is_power_cycle_due();
+        if substituted{
+            let next = device.get_count(HARD_REBOOTS)+1;
+            log::info!("Power cycling {} for the {}th time",device.get_serial(),next);
+            device.power_cycle();
+        }
+        else{
+            let next = device.get_count(REBOOTS)+1;
+            log::info!("Rebooting {} for the {}th time",device.get_serial(),next);
+            device.reboot();
+        }
+        let back = match self.timeout_s{
+            0 => true,
+            timeout => device.wait_for_login_prompt(Duration::from_secs(timeout)),
+        };
+        match substituted{
+            true if back => return Observation::Substituted,
+            _ => return Observation::Completed(back),
+        }
+    }
+    fn evaluate(&self, observation:&Observation) -> bool{
+        return matches!(observation, Observation::Completed(true) | Observation::Substituted);
+    }
+    fn get_record(&self, observation:&Observation) -> Option<&str>{
+        match observation{
+            Observation::Substituted => return Some(HARD_REBOOTS),
+            _ => return Some(REBOOTS),
+        }
+    }
+}
+
+#[derive(Clone,Debug,Deserialize,PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct WaitStep{
+    pub seconds: u64,
+}
+
+impl TestStep for WaitStep{
+    fn get_name(&self) -> &str{
+        return WAIT;
+    }
+    fn execute(&self, device:&mut Device) -> Observation{
+        device.sleep(Duration::from_secs(self.seconds));
+        return Observation::Completed(true);
+    }
+    fn evaluate(&self, observation:&Observation) -> bool{
+        return *observation == Observation::Completed(true);
+    }
+}
+
+//Runs a shell command; passes when it finishes and, if given, its output matches expect.
+#[derive(Clone,Debug,Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CommandStep{
+    pub command: String,
+    #[serde(default, with = "serde_regex_option")]
+    pub expect: Option<Regex>,
+    pub timeout_s: Option<u64>,
+}
+
+mod serde_regex_option{
+    use regex::Regex;
+    use serde::{Deserialize, Deserializer};
+
+    pub fn deserialize<'de, D:Deserializer<'de>>(deserializer:D) -> Result<Option<Regex>,D::Error>{
+        let pattern:Option<String> = Option::deserialize(deserializer)?;
+        match pattern{
+            Some(pattern) => return Regex::new(&pattern).map(Some).map_err(serde::de::Error::custom),
+            None => return Ok(None),
+        }
+    }
+}
+
+impl TestStep for CommandStep{
+    fn get_name(&self) -> &str{
+        return COMMAND;
+    }
+    fn execute(&self, device:&mut Device) -> Observation{
+        let timeout = self.timeout_s.map(Duration::from_secs).unwrap_or(DEFAULT_COMMAND_TIMEOUT);
+        match device.run_shell_command(&self.command,timeout){
+            Some(output) => {
+                log::debug!("{} printed {:?}",self.command,output);
+                return Observation::Output(output);
+            },
+            None => return Observation::Completed(false),
+        }
+    }
+    fn evaluate(&self, observation:&Observation) -> bool{
+        match (observation, &self.expect){
+            (Observation::Output(output), Some(expect)) => return expect.is_match(output),
+            (Observation::Output(_), None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+//Builds a step from the options given for it in a plan file (everything but kind and repeat).
+pub type StepFactory = Arc<dyn Fn(toml::Table) -> Result<Arc<dyn TestStep>,String> + Send + Sync>;
+
+//Maps the kind names used in plan files to steps.
+#[derive(Clone)]
+pub struct StepRegistry{
+    factories: HashMap<String,StepFactory>,
+}
+
+impl Debug for StepRegistry{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result{
+        f.debug_struct("StepRegistry").field("kinds", &self.get_kinds()).finish()
+    }
+}
+
+//Reads a step's options into its own type, so unknown or mistyped options are rejected.
+pub fn from_options<T:TestStep + DeserializeOwned + 'static>(options:toml::Table) -> Result<Arc<dyn TestStep>,String>{
+    let step:T = toml::Value::Table(options).try_into().map_err(|error:toml::de::Error| error.to_string().trim().to_string())?;
+    return Ok(Arc::new(step));
+}
+
+impl Default for StepRegistry{
+    fn default() -> Self {
+        let mut registry = StepRegistry { factories: HashMap::new() };
+        registry.register(BP, Arc::new(from_options::<BpStep>));
+        registry.register(TEMP, Arc::new(from_options::<TempStep>));
+        registry.register(BRIGHTNESS, Arc::new(from_options::<BrightnessStep>));
+        registry.register(REBOOT, Arc::new(from_options::<RebootStep>));
+        registry.register(WAIT, Arc::new(from_options::<WaitStep>));
+        registry.register(COMMAND, Arc::new(from_options::<CommandStep>));
+        return registry;
+    }
+}
+
+impl StepRegistry{
+    //Adds a kind, replacing any built-in of the same name.
+    pub fn register(&mut self, kind:&str, factory:StepFactory){
+        self.factories.insert(kind.to_string(),factory);
+    }
+
+    pub fn build(&self, kind:&str, options:toml::Table) -> Result<Arc<dyn TestStep>,String>{
+        match self.factories.get(kind){
+            Some(factory) => return factory(options),
+            None => return Err(format!("unknown step kind {}; expected one of {}",kind,self.get_kinds().join(", "))),
+        }
+    }
+
+    pub fn get_kinds(&self) -> Vec<String>{
+        let mut kinds:Vec<String> = self.factories.keys().cloned().collect();
+        kinds.sort();
+        return kinds;
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use std::fs;
+    use crate::plan::TestPlan;
+
+    //A step kind from outside the built-ins, as another crate would add one.
+    #[derive(Clone,Debug,Deserialize,PartialEq)]
+    #[serde(deny_unknown_fields)]
+    struct ChimeStep{
+        tone: u32,
+    }
+
+    impl TestStep for ChimeStep{
+        fn get_name(&self) -> &str{
+            return "chime";
+        }
+        fn execute(&self, _device:&mut Device) -> Observation{
+            return Observation::Value(self.tone as f64);
+        }
+        fn evaluate(&self, observation:&Observation) -> bool{
+            return *observation == Observation::Value(self.tone as f64);
+        }
+    }
+
+    fn options(text:&str) -> toml::Table{
+        return toml::from_str(text).unwrap();
+    }
+
+    #[test]
+    fn a_registered_step_kind_can_be_used_in_a_plan_file(){
+        let mut registry = StepRegistry::default();
+        registry.register("chime",Arc::new(from_options::<ChimeStep>));
+        assert!(registry.get_kinds().contains(&"chime".to_string()));
+        let path = "target/test-output/chime-plan.toml";
+        fs::create_dir_all("target/test-output").unwrap();
+        fs::write(path,"[[steps]]\nkind = \"chime\"\ntone = 440\nrepeat = 2\n\n[[steps]]\nkind = \"reboot\"\n").unwrap();
+        let plan = TestPlan::load_with(path,&registry).unwrap();
+        assert_eq!(plan.steps.len(),2);
+        assert_eq!(plan.steps[0].step.get_name(),"chime");
+        assert_eq!(plan.steps[0].repeat,2);
+        assert!(plan.steps[0].step.evaluate(&Observation::Value(440.0)));
+        assert!(TestPlan::load(path).unwrap_err().contains("unknown step kind chime"));
+    }
+
+    #[test]
+    fn step_options_are_read_into_the_step(){
+        let step = from_options::<WaitStep>(options("seconds = 5")).unwrap();
+        assert_eq!(step.get_name(),WAIT);
+        let reboot:RebootStep = toml::Value::Table(options("")).try_into().unwrap();
+        assert_eq!(reboot,RebootStep::default());
+    }
+
+    #[test]
+    fn unknown_or_mistyped_step_options_are_rejected(){
+        let error = from_options::<WaitStep>(options("seconds = 5\nsecs = 6")).unwrap_err();
+        assert!(error.contains("secs"),"{}",error);
+        assert!(from_options::<TempStep>(options("relay = 4")).is_err());
+        assert!(from_options::<BrightnessStep>(options("level = \"dim\"")).is_err());
+        assert!(from_options::<WaitStep>(options("seconds = \"five\"")).is_err());
+        assert!(from_options::<CommandStep>(options("command = \"true\"\nexpect = \"(\"")).is_err());
+        assert!(StepRegistry::default().build("dance",toml::Table::new()).unwrap_err().contains("unknown step kind dance"));
+    }
+
+    #[test]
+    fn bp_and_temp_pass_only_when_the_state_changes(){
+        for step in [&BpStep::default() as &dyn TestStep, &TempStep::default()]{
+            assert!(step.evaluate(&Observation::Transition { before: true, after: false }));
+            assert!(step.evaluate(&Observation::Transition { before: false, after: true }));
+            assert!(!step.evaluate(&Observation::Transition { before: true, after: true }));
+            assert!(!step.evaluate(&Observation::Transition { before: false, after: false }));
+            assert!(!step.evaluate(&Observation::Completed(true)));
+        }
+        assert_eq!(BpStep::default().get_record(&Observation::Completed(true)),Some(BP_TESTS));
+        assert_eq!(TempStep::default().get_record(&Observation::Completed(true)),Some(TEMP_TESTS));
+    }
+
+    #[test]
+    fn brightness_and_wait_pass_once_completed(){
+        for step in [&BrightnessStep { level: BrightnessLevel::Low } as &dyn TestStep, &WaitStep { seconds: 1 }]{
+            assert!(step.evaluate(&Observation::Completed(true)));
+            assert!(!step.evaluate(&Observation::Completed(false)));
+            assert_eq!(step.get_record(&Observation::Completed(true)),None);
+        }
+    }
+
+    #[test]
+    fn a_reboot_passes_when_the_unit_comes_back_and_counts_power_cycles_apart(){
+        let step = RebootStep::default();
+        assert!(step.evaluate(&Observation::Completed(true)));
+        assert!(step.evaluate(&Observation::Substituted));
+        assert!(!step.evaluate(&Observation::Completed(false)));
+        assert_eq!(step.get_record(&Observation::Completed(true)),Some(REBOOTS));
+        assert_eq!(step.get_record(&Observation::Substituted),Some(HARD_REBOOTS));
+    }
+
+    #[test]
+    fn a_command_passes_on_output_matching_its_expectation(){
+        let step:CommandStep = toml::Value::Table(options("command = \"uname\"\nexpect = \"^Linux\"")).try_into().unwrap();
+        assert!(step.evaluate(&Observation::Output("Linux unit 5.10".to_string())));
+        assert!(!step.evaluate(&Observation::Output("BusyBox".to_string())));
+        assert!(!step.evaluate(&Observation::Completed(false)));
+        let anything:CommandStep = toml::Value::Table(options("command = \"uname\"")).try_into().unwrap();
+        assert!(anything.evaluate(&Observation::Output(String::new())));
+        assert!(!anything.evaluate(&Observation::Completed(false)));
+    }
+
+    #[test]
+    fn only_bp_holds_its_own_place(){
+        assert!(BpStep::default().holds_own_place());
+        assert!(!TempStep::default().holds_own_place());
+        assert!(!RebootStep::default().holds_own_place());
+        assert!(!ChimeStep { tone: 1 }.holds_own_place());
+    }
+}
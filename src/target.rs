@@ -0,0 +1,107 @@
+use std::collections::BTreeMap;
+use serde::Deserialize;
+use crate::device::{Counts, COUNT_LABELS};
+
+//Written to a unit's output file once it has met every target.
+pub const TARGETS_MET: &str = "Targets met";
+
+//Lifetime totals a unit should reach before it is retired, keyed by count
+//label, e.g. Reboots = 10000. No targets means the unit is never retired.
+#[derive(Clone,Debug,Default,Deserialize,PartialEq)]
+#[serde(transparent)]
+pub struct Targets{
+    values: BTreeMap<String,u64>,
+}
+
+impl Targets{
+    pub fn set(&mut self, label:&str, value:u64){
+        self.values.insert(label.to_string(),value);
+    }
+
+    //Rejects labels that are not counts a unit keeps, such as a misspelling,
+    //which could otherwise never be met.
+    pub fn validate(&self) -> Result<(),String>{
+        for label in self.values.keys(){
+            if !COUNT_LABELS.contains(&label.as_str()){
+                return Err(format!("unknown target {:?}; expected one of {}",label,COUNT_LABELS.join(", ")));
+            }
+        }
+        return Ok(());
+    }
+
+    pub fn is_empty(&self) -> bool{
+        return self.values.is_empty();
+    }
+
+    //Targets in other replace these label by label.
+    pub fn merge(&mut self, other:&Targets){
+        for (label, value) in other.values.iter(){
+            self.values.insert(label.clone(),*value);
+        }
+    }
+
+    pub fn is_met(&self, counts:&Counts) -> bool{
+        return !self.is_empty() && self.values.iter().all(|(label, target)| counts.get(label) >= *target);
+    }
+
+    //How far each unmet target still has to go.
+    pub fn get_remaining(&self, counts:&Counts) -> Vec<(String,u64)>{
+        return self.values.iter()
+            .filter(|(label, target)| counts.get(label) < **target)
+            .map(|(label, target)| (label.clone(), target - counts.get(label)))
+            .collect();
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, u64)>{
+        return self.values.iter().map(|(label, value)| (label.as_str(), *value));
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::device::{BP_TESTS, REBOOTS};
+
+    fn counts(reboots:u64, bp_tests:u64) -> Counts{
+        let mut counts = Counts::default();
+        counts.set(REBOOTS,reboots);
+        counts.set(BP_TESTS,bp_tests);
+        return counts;
+    }
+
+    #[test]
+    fn no_targets_are_never_met(){
+        assert!(!Targets::default().is_met(&counts(1000,1000)));
+    }
+
+    #[test]
+    fn met_only_when_every_target_is_reached(){
+        let mut targets = Targets::default();
+        targets.set(REBOOTS,10);
+        targets.set(BP_TESTS,5);
+        assert!(!targets.is_met(&counts(10,4)));
+        assert!(targets.is_met(&counts(10,5)));
+        assert!(targets.is_met(&counts(11,6)));
+        assert_eq!(targets.get_remaining(&counts(7,5)),vec![(REBOOTS.to_string(),3)]);
+    }
+
+    #[test]
+    fn merge_replaces_label_by_label(){
+        let mut targets = Targets::default();
+        targets.set(REBOOTS,10);
+        targets.set(BP_TESTS,5);
+        let mut other = Targets::default();
+        other.set(REBOOTS,20);
+        targets.merge(&other);
+        assert_eq!(targets.iter().collect::<Vec<_>>(),vec![(REBOOTS,20),(BP_TESTS,5)]);
+    }
+
+    #[test]
+    fn unknown_labels_are_rejected(){
+        let mut targets = Targets::default();
+        targets.set(REBOOTS,10);
+        assert!(targets.validate().is_ok());
+        targets.set("Rebots",10);
+        assert!(targets.validate().is_err());
+    }
+}
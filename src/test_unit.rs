@@ -0,0 +1,220 @@
+use std::{fs, io::{Read, Write}, net::{TcpListener, TcpStream}, sync::{Arc, Mutex, MutexGuard}, thread, time::Duration};
+use crate::device::{self, Device};
+use crate::gpio_facade::{MockBackend, RelayAllocator, RelaySettings};
+use crate::tty::{Response, SerialSettings, TTY};
+
+const READ_TIMEOUT:Duration = Duration::from_millis(50);
+const SHELL_PROMPT: &str = "root@unit:~# ";
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Mode{
+    Login,
+    Shell,
+    Menu,
+}
+
+//What the unit's temp reading shows.
+#[derive(Clone,Debug)]
+pub enum Temp{
+    Fixed(u32),
+    //On while the mock relay line is energised (driven high).
+    Relay(MockBackend, u8),
+}
+
+#[derive(Debug)]
+pub struct UnitState{
+    pub mode: Mode,
+    pub serial: String,
+    pub temp: Temp,
+    //How many state checks a BP reads as running for after it starts.
+    pub bp_checks: u32,
+    bp_checks_left: u32,
+    //Reads everything and answers nothing, like a hung unit.
+    pub silent: bool,
+    pub reboots: u64,
+    //A power relay line: energising it cuts power, so the unit comes back at
+    //its login prompt and a hung unit answers again.
+    pub power: Option<(MockBackend, u8)>,
+    pub power_cycles: usize,
+    //Every command received, in order.
+    pub received: Vec<String>,
+}
+
+//A unit on a console server, for tests that need a Device. It answers the
+//prompts and menus the device's state machine expects, the way the real
+//firmware does, and keeps answering across reconnects.
+pub struct TestUnit{
+    address: String,
+    state: Arc<Mutex<UnitState>>,
+}
+
+impl TestUnit{
+    pub fn start(serial:&str) -> Self{
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let state = Arc::new(Mutex::new(UnitState {
+            mode: Mode::Login,
+            serial: serial.to_string(),
+            temp: Temp::Fixed(36),
+            bp_checks: 1,
+            bp_checks_left: 0,
+            silent: false,
+            reboots: 0,
+            power: None,
+            power_cycles: 0,
+            received: Vec::new(),
+        }));
+        let shared = state.clone();
+        thread::spawn(move ||{
+            for stream in listener.incoming(){
+                match stream{
+                    Ok(stream) => serve(stream,&shared),
+                    Err(_) => return,
+                }
+            }
+        });
+        return TestUnit { address, state };
+    }
+
+    pub fn state(&self) -> MutexGuard<'_, UnitState>{
+        return self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    }
+
+    pub fn get_location(&self) -> String{
+        return format!("tcp://{}",self.address);
+    }
+
+    pub fn open(&self) -> TTY{
+        let settings = SerialSettings { timeout: READ_TIMEOUT, lock_directory: None, ..Default::default() };
+        return TTY::new_with_settings(&self.get_location(),&settings).unwrap();
+    }
+
+    //A device at the login prompt with its serial set and no counts from
+    //earlier test runs.
+    pub fn device_with_relays(&self, relays:Arc<RelayAllocator>) -> Device{
+        let serial = self.state().serial.clone();
+        _ = fs::remove_file(device::output_path(&serial));
+        _ = fs::remove_file(device::quarantine_path(&serial));
+        let mut device = Device::new_with_relays(self.open(),Some(Response::LoginPrompt),relays).unwrap();
+        device.set_serial(&serial);
+        return device;
+    }
+
+    pub fn device(&self) -> Device{
+        return self.device_with_relays(no_relays());
+    }
+
+    pub fn get_received(&self) -> Vec<String>{
+        return self.state().received.clone();
+    }
+}
+
+pub fn no_relays() -> Arc<RelayAllocator>{
+    return RelayAllocator::new(Arc::new(MockBackend::new()),RelaySettings { addresses: Vec::new(), ..Default::default() });
+}
+
+fn serve(mut stream:TcpStream, state:&Mutex<UnitState>){
+    let mut pending = String::new();
+    let mut buffer = [0u8; 1024];
+    loop{
+        let read_count = match stream.read(&mut buffer){
+            Ok(0) | Err(_) => return,
+            Ok(read_count) => read_count,
+        };
+        pending.push_str(&String::from_utf8_lossy(&buffer[..read_count]));
+        let mut state = state.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        notice_power_cycles(&mut state);
+        let mut reply = String::new();
+        while let Some((command, rest)) = next_command(&pending,state.mode){
+            let command = command.to_string();
+            pending = rest.to_string();
+            state.received.push(command.clone());
+            if !state.silent{
+                reply.push_str(&answer(&mut state,&command));
+            }
+        }
+        drop(state);
+        if !reply.is_empty() && stream.write_all(reply.as_bytes()).is_err(){
+            return;
+        }
+    }
+}
+
+//The relay can only be seen once the next command arrives, which is also the
+//first moment the unit could have answered after powering back up.
+fn notice_power_cycles(state:&mut UnitState){
+    let power_cycles = match state.power{
+        Some((ref backend, address)) => backend.get_changes().iter().filter(|change| change.address == address && change.high).count(),
+        None => return,
+    };
+    if power_cycles > state.power_cycles{
+        state.power_cycles = power_cycles;
+        state.mode = Mode::Login;
+        state.silent = false;
+        state.bp_checks_left = 0;
+    }
+}
+
+//Menus act on single keys; the login prompt and shell on whole lines.
+fn next_command(pending:&str, mode:Mode) -> Option<(&str, &str)>{
+    if mode == Mode::Menu && !pending.starts_with('q'){
+        let mut characters = pending.chars();
+        let key = characters.next()?;
+        return Some((&pending[..key.len_utf8()], characters.as_str()));
+    }
+    let end = pending.find('\n')? + 1;
+    return Some(pending.split_at(end));
+}
+
+fn answer(state:&mut UnitState, command:&str) -> String{
+    match state.mode{
+        Mode::Login => match command.trim(){
+            "root" => {
+                state.mode = Mode::Shell;
+                return SHELL_PROMPT.to_string();
+            },
+            line if line.contains("debugmenu") => {
+                state.mode = Mode::Menu;
+                return ">".to_string();
+            },
+            _ => return "login:".to_string(),
+        },
+        Mode::Shell => match command.trim(){
+            "exit" => {
+                state.mode = Mode::Login;
+                return "login:".to_string();
+            },
+            line if line.contains("debugmenu") => {
+                state.mode = Mode::Menu;
+                return ">".to_string();
+            },
+            line if line.starts_with("echo OUTPUT") => return format!("OUTPUT_START\n{}\nOUTPUT_END\n{}",state.serial,SHELL_PROMPT),
+            _ => return SHELL_PROMPT.to_string(),
+        },
+        Mode::Menu => match command{
+            "q\n" => {
+                state.mode = Mode::Login;
+                state.reboots += 1;
+                state.bp_checks_left = 0;
+                return "[    0.000000] Booting Linux\nlogin:".to_string();
+            },
+            "N" => {
+                state.bp_checks_left = state.bp_checks;
+                return ">".to_string();
+            },
+            "n" => {
+                let running = state.bp_checks_left > 0;
+                state.bp_checks_left = state.bp_checks_left.saturating_sub(1);
+                return format!("Check NIBP In Progress: {}\n>",if running { "True" } else { "False" });
+            },
+            "h" => {
+                let reading = match state.temp{
+                    Temp::Fixed(reading) => reading,
+                    Temp::Relay(ref backend, address) => if backend.is_high(address) { 36 } else { 0 },
+                };
+                return format!("Temp: {}\n>",reading);
+            },
+            _ => return ">".to_string(),
+        },
+    }
+}
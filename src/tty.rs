@@ -1,13 +1,68 @@
-use std::{collections::HashMap, io::{BufReader, Write, Read}, time::Duration};
+use std::{collections::{HashMap, VecDeque}, io::{BufReader, Write, Read}, time::{Duration, Instant}};
 use once_cell::sync::Lazy;
-use serialport::SerialPort;
+use serialport::{SerialPort, DataBits, Parity, StopBits, FlowControl};
 use derivative::Derivative;
+use serde::Deserialize;
+use crate::network::{self,NetworkPort};
+use crate::lock::{self,LockFile};
 
-const BAUD_RATE:u32 = 115200;
-const SERIAL_READ_TIMEOUT: std::time::Duration = Duration::from_millis(500);
+pub const BAUD_RATE:u32 = 115200;
+pub const SERIAL_READ_TIMEOUT: std::time::Duration = Duration::from_millis(500);
+//Rates tried in order when automatic baud detection is enabled.
+pub const COMMON_BAUD_RATES:[u32;6] = [115200,57600,38400,19200,9600,230400];
+//How many of the most recent writes and reads a port keeps for diagnosis.
+const TRANSCRIPT_LENGTH:usize = 50;
+//Time the unit is given to act on a write. The test unit needs none.
+const WRITE_SETTLE:Duration = if cfg!(test) { Duration::ZERO } else { Duration::from_millis(500) };
 
+#[derive(Clone,Debug,PartialEq)]
+pub struct SerialSettings{
+    pub baud_rate: u32,
+    pub timeout: Duration,
+    pub data_bits: DataBits,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+    pub flow_control: FlowControl,
+    pub auto_baud: bool,
+    pub auto_baud_rates: Vec<u32>,
+    //Where to place UUCP lock files; None disables them. Local ports are
+    //additionally opened with TIOCEXCL by serialport.
+    pub lock_directory: Option<String>,
+}
+
+impl Default for SerialSettings{
+    fn default() -> Self {
+        SerialSettings {
+            baud_rate: BAUD_RATE,
+            timeout: SERIAL_READ_TIMEOUT,
+            data_bits: DataBits::Eight,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+            flow_control: FlowControl::None,
+            auto_baud: false,
+            auto_baud_rates: COMMON_BAUD_RATES.to_vec(),
+            lock_directory: Some(lock::DEFAULT_LOCK_DIRECTORY.to_string()),
+        }
+    }
+}
+
+impl SerialSettings{
+    fn open(&self, serial_location:&str) -> serialport::Result<Box<dyn SerialPort>>{
+        if network::is_network_location(serial_location){
+            return Ok(Box::new(NetworkPort::open(serial_location,self)?));
+        }
+        serialport::new(serial_location,self.baud_rate)
+            .timeout(self.timeout)
+            .data_bits(self.data_bits)
+            .parity(self.parity)
+            .stop_bits(self.stop_bits)
+            .flow_control(self.flow_control)
+            .open()
+    }
+}
 
-#[derive(Eq,Derivative,Debug)]
+
+#[derive(Clone,Copy,Eq,Derivative,Debug,Deserialize)]
 #[derivative(PartialEq, Hash)]
 pub enum Command{
     Quit,
@@ -23,9 +78,17 @@ pub enum Command{
     Login,
     DebugMenu,
     Newline,
+    Logout,
 }
 
-#[derive(Clone,Eq,Derivative,Debug)]
+impl Command{
+    //Commands that only ask the unit something, so sending one twice is harmless.
+    pub fn is_query(&self) -> bool{
+        return matches!(self, Command::CheckBPState | Command::ReadTemp);
+    }
+}
+
+#[derive(Clone,Eq,Derivative,Debug,Deserialize)]
 #[derivative(Copy,PartialEq, Hash)]
 pub enum Response{
     PasswordPrompt,
@@ -57,6 +120,7 @@ const COMMAND_MAP:Lazy<HashMap<Command,&str>> = Lazy::new(||HashMap::from([
     (Command::RedrawMenu,"?"),
     (Command::DebugMenu," python3 -m debugmenu; shutdown -r now\n"),
     (Command::Newline,"\n"),
+    (Command::Logout,"exit\n"),
 ]));
 
 const RESPONSES:[(&str,Response);10] = [
@@ -73,47 +137,205 @@ const RESPONSES:[(&str,Response);10] = [
 ];
 
 pub struct TTY{
-    tty: Box<dyn SerialPort>,
-    failed_read_count: u8
+    //None while closed for a reconnect that has not yet succeeded.
+    tty: Option<Box<dyn SerialPort>>,
+    location: String,
+    settings: SerialSettings,
+    _lock: Option<LockFile>,
+    failed_read_count: u8,
+    last_response: Instant,
+    transcript: VecDeque<String>,
 }
 impl std::fmt::Debug for TTY{
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result{
         f.debug_struct("TTY")
-        .field("Serial port name",&self.tty.name().unwrap_or("Unknown".to_string()))
+        .field("Serial port name",&self.location)
+        .field("Baud rate",&self.settings.baud_rate)
         .finish()
     }
 }
 
 impl TTY{
     pub fn new(serial_location:&str) -> Option<Self>{
-        let possible_tty = serialport::new(serial_location,BAUD_RATE).timeout(SERIAL_READ_TIMEOUT).open();
+        return TTY::new_with_settings(serial_location, &SerialSettings::default());
+    }
+
+    pub fn new_with_settings(serial_location:&str, settings:&SerialSettings) -> Option<Self>{
+        let port_lock = match settings.lock_directory{
+            Some(ref lock_directory) => {
+                match LockFile::acquire(&lock::serial_lock_path(lock_directory, serial_location)){
+                    Ok(port_lock) => Some(port_lock),
+                    Err(error) => {
+                        log::error!("Cannot open {}: {}",serial_location,error);
+                        return None;
+                    }
+                }
+            },
+            None => None
+        };
+        if settings.auto_baud{
+            return TTY::detect_baud(serial_location, settings, port_lock);
+        }
+        let possible_tty = settings.open(serial_location);
         if let Ok(tty) = possible_tty{
             Some(TTY { 
-                tty,
-                failed_read_count: 0
+                tty: Some(tty),
+                location: serial_location.to_string(),
+                settings: settings.clone(),
+                _lock: port_lock,
+                failed_read_count: 0,
+                last_response: Instant::now(),
+                transcript: VecDeque::new(),
             })
         } else{
             None
         }
     }
 
+    //Tries the configured rate first, then each of the fallback rates, until the device
+    //answers a newline with something that matches a known prompt. Garbage from a
+    //mismatched rate reads as Response::Other, so it is not accepted.
+    fn detect_baud(serial_location:&str, settings:&SerialSettings, port_lock:Option<LockFile>) -> Option<Self>{
+        let mut rates = vec![settings.baud_rate];
+        for rate in settings.auto_baud_rates.iter(){
+            if !rates.contains(rate){
+                rates.push(*rate);
+            }
+        }
+        for rate in rates{
+            let mut attempt_settings = settings.clone();
+            attempt_settings.baud_rate = rate;
+            attempt_settings.auto_baud = false;
+            //The lock is already held for the whole detection.
+            attempt_settings.lock_directory = None;
+            log::debug!("Trying baud rate {} on {}",rate,serial_location);
+            if let Some(mut port) = TTY::new_with_settings(serial_location, &attempt_settings){
+                port.write_to_device(Command::Newline);
+                let response = port.read_from_device(None);
+                let detected = match response{
+                    Response::Other | Response::Empty => false,
+                    //A lone '>' or '[' turns up in line noise at the wrong rate,
+                    //so these only count if a second read agrees.
+                    Response::DebugMenuReady | Response::DebugMenuWithContinuedMessage | Response::Rebooting => {
+                        port.write_to_device(Command::Newline);
+                        port.read_from_device(None) == response
+                    },
+                    _ => true,
+                };
+                if detected{
+                    log::info!("Detected baud rate {} on {} ({:?})",rate,serial_location,response);
+                    port.settings.auto_baud = settings.auto_baud;
+                    port.settings.lock_directory = settings.lock_directory.clone();
+                    port._lock = port_lock;
+                    return Some(port);
+                }
+            }
+        }
+        log::warn!("Unable to detect baud rate on {}",serial_location);
+        return None;
+    }
+
+    //How long since the device last sent anything at all.
+    pub fn time_since_response(&self) -> Duration{
+        return self.last_response.elapsed();
+    }
+
+    //Recent traffic, oldest first: "> " for what was written, "< " for what was read.
+    pub fn get_transcript(&self) -> Vec<String>{
+        return self.transcript.iter().cloned().collect();
+    }
+
+    fn note(&mut self, direction:&str, text:&str){
+        if self.transcript.len() >= TRANSCRIPT_LENGTH{
+            self.transcript.pop_front();
+        }
+        self.transcript.push_back(format!("{} {:?}",direction,text));
+    }
+
+    //Closes and reopens the port with the same settings. The old handle is
+    //closed first, as local ports are opened exclusively. On failure the port
+    //stays closed: writes fail and reads come back empty until a later
+    //reconnect succeeds.
+    pub fn reconnect(&mut self) -> bool{
+        self.tty = None;
+        match self.settings.open(&self.location){
+            Ok(tty) => {
+                self.tty = Some(tty);
+                self.failed_read_count = 0;
+                return true;
+            },
+            Err(error) => {
+                log::warn!("Unable to reopen {}",self.location);
+                log::debug!("{}",error);
+                return false;
+            }
+        }
+    }
+
+    pub fn get_name(&self) -> String{
+        return self.location.clone();
+    }
+
+    pub fn get_settings(&self) -> &SerialSettings{
+        return &self.settings;
+    }
+
     pub fn write_to_device(&mut self,command:Command) -> bool {
-        log::debug!("writing {:?} to tty {}...", command, self.tty.name().unwrap_or("unknown".to_string()));
-        let output = self.tty.write_all(COMMAND_MAP.get(&command).unwrap().as_bytes()).is_ok();
-        _ = self.tty.flush();
-        std::thread::sleep(std::time::Duration::from_millis(500));
+        let text = COMMAND_MAP.get(&command).unwrap().to_string();
+        return self.write_text(&format!("{:?}",command),&text);
+    }
+
+    //For text that is not in COMMAND_MAP, such as configurable shell commands.
+    pub fn write_raw(&mut self,text:&str) -> bool {
+        return self.write_text(&format!("{:?}",text),text);
+    }
+
+    fn write_text(&mut self, description:&str, text:&str) -> bool{
+        log::debug!("writing {} to tty {}...", description, self.location);
+        self.note(">",text);
+        let output = match self.tty{
+            Some(ref mut tty) => {
+                let output = tty.write_all(text.as_bytes()).is_ok();
+                _ = tty.flush();
+                output
+            },
+            None => false,
+        };
+        std::thread::sleep(WRITE_SETTLE);
         return output;
     }
 
-    pub fn read_from_device(&mut self,_break_char:Option<&str>) -> Response {
-        let mut reader = BufReader::new(&mut self.tty);
+    //Everything available until the read timeout; nothing if the port is closed.
+    fn read_bytes(&mut self) -> Vec<u8>{
         let mut read_buffer: Vec<u8> = Vec::new();
-        _ = reader.read_to_end(&mut read_buffer);
+        match self.tty{
+            Some(ref mut tty) => _ = BufReader::new(tty).read_to_end(&mut read_buffer),
+            None => std::thread::sleep(self.settings.timeout),
+        }
+        return read_buffer;
+    }
+
+    //Everything available until the read timeout, without matching against RESPONSES.
+    pub fn read_raw(&mut self) -> String {
+        let read_buffer = self.read_bytes();
+        let read_line = String::from_utf8_lossy(read_buffer.as_slice()).to_string();
+        if !read_buffer.is_empty(){
+            self.last_response = Instant::now();
+            self.note("<",&read_line);
+        }
+        log::trace!("Raw read of {:?} from tty {}",read_line,self.location);
+        return read_line;
+    }
+
+    pub fn read_from_device(&mut self,_break_char:Option<&str>) -> Response {
+        let read_buffer = self.read_bytes();
         if read_buffer.len() > 0 {
+            self.last_response = Instant::now();
             let read_line:String = String::from_utf8_lossy(read_buffer.as_slice()).to_string();
+            self.note("<",&read_line);
             for (string,enum_value) in RESPONSES{
                 if read_line.contains(string){
-                   log::debug!("Successful read of {:?} from tty {}, which matches pattern {:?}",read_line,self.tty.name().unwrap_or("unknown shell".to_string()),enum_value);
+                   log::debug!("Successful read of {:?} from tty {}, which matches pattern {:?}",read_line,self.location,enum_value);
                    self.failed_read_count = 0;
                     return enum_value;
                 }
@@ -121,6 +343,7 @@ impl TTY{
             return Response::Other;
         }
         else {
+            self.note("<","");
             log::debug!("Read an empty string. Possible read error.");
             //Due to a linux kernel power-saving setting that is overly complicated to fix,
             //Serial connections will drop for a moment before re-opening, at seemingly-random
@@ -128,11 +351,71 @@ impl TTY{
             self.failed_read_count += 1;
             if self.failed_read_count >= 15{
                 self.failed_read_count = 0;
-                let tty_location = self.tty.name().expect("Unable to read tty name!");
-                self.tty = serialport::new(tty_location,BAUD_RATE).timeout(SERIAL_READ_TIMEOUT).open().expect("Unable to open serial connection!");
-                return self.read_from_device(_break_char);
+                if self.reconnect(){
+                    return self.read_from_device(_break_char);
+                }
             }
             return Response::Empty;
         };
     }
 }
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use std::{io::{Read, Write}, net::TcpListener, thread};
+
+    //A console that answers each newline on its nth connection with the nth
+    //list of replies, then stays quiet. One connection is opened per rate tried.
+    fn console(replies:Vec<Vec<&'static str>>) -> String{
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        thread::spawn(move ||{
+            for connection_replies in replies{
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut replies = connection_replies.into_iter();
+                let mut byte = [0u8];
+                while let Ok(1) = stream.read(&mut byte){
+                    if byte[0] == b'\n'{
+                        if let Some(reply) = replies.next(){
+                            _ = stream.write_all(reply.as_bytes());
+                        }
+                    }
+                }
+            }
+        });
+        return format!("tcp://{}",address);
+    }
+
+    fn auto_baud() -> SerialSettings{
+        return SerialSettings {
+            baud_rate: 9600,
+            auto_baud: true,
+            auto_baud_rates: vec![9600, 115200],
+            timeout: Duration::from_millis(200),
+            lock_directory: None,
+            ..Default::default()
+        };
+    }
+
+    #[test]
+    fn a_login_prompt_settles_the_baud_rate_at_once(){
+        let location = console(vec![vec!["login:"]]);
+        let port = TTY::new_with_settings(&location,&auto_baud()).unwrap();
+        assert_eq!(port.get_settings().baud_rate,9600);
+        assert!(port.get_settings().auto_baud);
+    }
+
+    #[test]
+    fn a_lone_prompt_counts_only_when_a_second_read_agrees(){
+        let location = console(vec![vec![">", "\u{fffd}~x"], vec![">", ">"]]);
+        let port = TTY::new_with_settings(&location,&auto_baud()).unwrap();
+        assert_eq!(port.get_settings().baud_rate,115200);
+    }
+
+    #[test]
+    fn line_noise_at_every_rate_detects_nothing(){
+        let location = console(vec![vec!["\u{fffd}\u{fffd}"], vec!["["]]);
+        assert!(TTY::new_with_settings(&location,&auto_baud()).is_none());
+    }
+}